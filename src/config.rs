@@ -1,12 +1,37 @@
 //! The configuration details for `mdfmt`. This class defines the configuration options for
 //! serialization and what is acceptable from the command line.
 
-use failure::Fail;
+// `failure`'s derive macro generates its impls inside of an anonymous constant
+#![allow(non_local_definitions)]
+
+use crate::editorconfig;
+use failure::{Error, Fail};
 use getset::Getters;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use schemars::{schema::Schema, schema_for, JsonSchema};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use toml::value::{Table, Value};
+
+/// The narrowest line width that the formatter can work with
+pub const MIN_LINE_WIDTH: usize = 20;
+
+/// The files that are looked up in the directories above the input file for a project config
+///
+/// Each file is paired with the keys of the table that holds the config inside of it, which is
+/// empty if the whole file is the config. Files earlier in the list take precedence over later
+/// files in the same directory.
+const PROJECT_CONFIG_FILES: &[(&str, &[&str])] = &[
+    (".mdfmt.toml", &[]),
+    ("Cargo.toml", &["package", "metadata", "mdfmt"]),
+    ("pyproject.toml", &["tool", "mdfmt"]),
+];
 
 /// Format markdown files
 #[derive(StructOpt, Debug, Getters, Clone)]
@@ -16,24 +41,63 @@ pub struct Opt {
     #[structopt(short = "i", long = "in-place")]
     pub in_place: bool,
 
-    /// The input file to format
+    /// The input file to format. If this is a directory, every markdown file inside of it is
+    /// formatted in place.
     #[structopt(parse(from_os_str))]
-    pub input_file: PathBuf,
+    pub input_file: Option<PathBuf>,
 
     /// A configuration file specifying the options to use when formatting the markdown file. Any
     /// command line options will override options from the [optional] config file.
     #[structopt(short = "c", long = "config", parse(from_os_str))]
     pub config_file: Option<PathBuf>,
+
+    /// Print the resolved configuration as TOML instead of formatting a file, noting where each
+    /// option came from. Use `--print-config=default` to print the default configuration.
+    #[structopt(long = "print-config", require_equals = true)]
+    pub print_config: Option<Option<String>>,
+
+    /// The preset to use as the base of the configuration, which takes precedence over the preset
+    /// from any config file.
+    #[structopt(long = "preset")]
+    pub preset: Option<String>,
+
+    /// Print a JSON Schema for config files instead of formatting a file, which editors can use
+    /// to validate and complete config files.
+    #[structopt(long = "config-schema")]
+    pub config_schema: bool,
 }
 
 impl Opt {
     /// Return whether the presented command line parameters are valid
     pub fn valid(&self) -> Result<(), OptError> {
-        if !self.input_file.is_file() {
-            return Err(OptError::InvalidFile {
-                filename: self.input_file.to_path_buf(),
-                parameter: "input_file".to_string(),
-            });
+        // An input file is only optional if the configuration is printed instead
+        if self.input_file.is_none() && self.print_config.is_none() && !self.config_schema {
+            return Err(OptError::MissingInputFile);
+        }
+
+        if let Some(input_file) = &self.input_file {
+            if !input_file.is_file() && !input_file.is_dir() {
+                return Err(OptError::InvalidFile {
+                    filename: input_file.to_path_buf(),
+                    parameter: "input_file".to_string(),
+                });
+            }
+
+            // Formatted files are printed one after another otherwise, which can't be told apart
+            if input_file.is_dir() && !self.in_place {
+                return Err(OptError::DirectoryNotInPlace {
+                    directory: input_file.to_path_buf(),
+                });
+            }
+        }
+
+        if let Some(Some(value)) = &self.print_config {
+            if value != "default" {
+                return Err(OptError::InvalidValue {
+                    value: value.to_string(),
+                    parameter: "print_config".to_string(),
+                });
+            }
         }
 
         if let Some(config) = &self.config_file {
@@ -58,13 +122,74 @@ pub enum OptError {
         filename: PathBuf,
         parameter: String,
     },
+
+    #[fail(display = "Missing input file")]
+    MissingInputFile,
+
+    #[fail(display = "Invalid value: {:?} for {}", value, parameter)]
+    InvalidValue { value: String, parameter: String },
+
+    #[fail(
+        display = "Directory {:#?} can only be formatted in place (with --in-place)",
+        directory
+    )]
+    DirectoryNotInPlace { directory: PathBuf },
+}
+
+/// Validation errors for config options
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "Invalid config options:{}", violations)]
+    Invalid { violations: Violations },
+
+    #[fail(display = "Invalid {}: {}", origin, message)]
+    InvalidFile { origin: Origin, message: String },
+}
+
+/// A config option that has an invalid value
+#[derive(Debug)]
+pub struct Violation {
+    /// The key of the option in the config file
+    key: String,
+
+    /// Where the value of the option came from
+    origin: Origin,
+
+    /// What is wrong with the value
+    message: String,
+}
+
+/// Every config option that has an invalid value
+///
+/// Each violation is displayed on its own line.
+#[derive(Debug)]
+pub struct Violations(Vec<Violation>);
+
+impl fmt::Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for violation in &self.0 {
+            write!(
+                f,
+                "\n  `{}` (from the {}) {}",
+                violation.key, violation.origin, violation.message
+            )?;
+        }
+        Ok(())
+    }
 }
 
 /// The struct representing the configuration options for the app.
-#[derive(Serialize, Deserialize, Getters, Debug)]
+///
+/// Any option that is missing from a configuration file falls back to its default value.
+#[derive(Serialize, Deserialize, JsonSchema, Getters, Debug)]
+#[serde(default, deny_unknown_fields)]
 #[get = "pub"]
 pub struct Config {
-    /// The max line width for the output file.
+    /// The preset that the config is based on. Options that are set explicitly take precedence
+    /// over the options of the preset.
+    preset: Option<Preset>,
+
+    /// The max line width for the output file. This must be at least 20.
     #[serde(rename = "line-width")]
     line_width: usize,
 
@@ -72,40 +197,732 @@ pub struct Config {
     #[serde(rename = "indent-width")]
     indent_width: usize,
 
-    /// The symbol to use to denote lists. This can either be `-` or `*`.
+    /// Whether nested content is indented with `space` or `tab` characters.
+    #[serde(rename = "indent-style")]
+    indent_style: IndentStyle,
+
+    /// The number of columns that a tab takes up when the length of a line is measured.
+    #[serde(rename = "tab-width")]
+    tab_width: usize,
+
+    /// The symbol to use to denote lists. This can be `-`, `*` or `+`.
     #[serde(rename = "list-delim")]
     list_delim: ListDelimiter,
+
+    /// The symbols to use to denote lists at each nesting level, which are cycled through for
+    /// lists that are nested deeper than the number of symbols. If this is empty, every level
+    /// uses `list-delim`.
+    #[serde(rename = "list-delims")]
+    list_delims: Vec<ListDelimiter>,
+
+    /// The symbol to use for thematic breaks (horizontal rules). This can be `-`, `*` or `_`.
+    #[serde(rename = "thematic-break")]
+    thematic_break: ThematicBreak,
+
+    /// How many times the thematic break symbol is repeated. A width of `0` draws a rule that
+    /// spans the entire line width. Markdown requires at least three symbols, so smaller widths
+    /// are rounded up to three.
+    #[serde(rename = "thematic-break-width")]
+    thematic_break_width: usize,
+
+    /// The symbol used to mark emphasis. This can be `*` or `_`. Emphasis inside of a word always
+    /// uses `*`, because `_` can't be used there.
+    emphasis: EmphasisStyle,
+
+    /// The style used to mark hard line breaks. This can either be `backslash` or `two-spaces`.
+    #[serde(rename = "hard-break")]
+    hard_break: HardBreak,
+
+    /// How autolinks are written. `preserve` keeps URLs the way they were written (either bare or
+    /// in angle brackets), and `angle-brackets` wraps bare URLs in angle brackets, which is
+    /// portable to CommonMark renderers that don't detect bare URLs.
+    autolinks: AutolinkStyle,
+
+    /// How paragraphs are wrapped. `fill` puts as many words on a line as possible, `sentence`
+    /// starts every sentence on a new line, `preserve` keeps the line breaks from the source
+    /// document and `never` joins each paragraph onto a single line. Only `fill` and `sentence`
+    /// wrap lines at the line width.
+    wrap: WrapMode,
+
+    /// The algorithm used to wrap lines at the line width. `greedy` puts as many words on each
+    /// line as possible, and `optimal` balances the line lengths of a paragraph so the right edge
+    /// is less ragged.
+    #[serde(rename = "wrap-algorithm")]
+    wrap_algorithm: WrapAlgorithm,
+
+    /// Whether list items are separated by blank lines. `preserve` keeps tight lists tight and
+    /// loose lists loose, while `tight` and `loose` make every list the same.
+    #[serde(rename = "list-spacing")]
+    list_spacing: ListSpacing,
+
+    /// The number of blank lines before a top level heading.
+    #[serde(rename = "blank-lines-before-heading")]
+    blank_lines_before_heading: usize,
+
+    /// The number of blank lines after a top level heading.
+    #[serde(rename = "blank-lines-after-heading")]
+    blank_lines_after_heading: usize,
+
+    /// The number of blank lines before and after a top level list. There is always at least one
+    /// blank line after a list, because the block after it would otherwise become part of it.
+    #[serde(rename = "blank-lines-around-lists")]
+    blank_lines_around_lists: usize,
+
+    /// The characters that end each line. `lf` and `crlf` always use `\n` and `\r\n`, `native`
+    /// uses the line ending of the current platform and `auto` keeps the line ending that the
+    /// input file uses.
+    #[serde(rename = "line-ending")]
+    line_ending: LineEnding,
+
+    /// Where the value of each option came from. Options that aren't in here have their default
+    /// value.
+    #[serde(skip)]
+    origins: BTreeMap<String, Origin>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            preset: None,
             line_width: 80,
             indent_width: 4,
+            indent_style: IndentStyle::Space,
+            tab_width: 4,
             list_delim: ListDelimiter::Asterisk,
+            list_delims: Vec::new(),
+            thematic_break: ThematicBreak::Dash,
+            thematic_break_width: 3,
+            emphasis: EmphasisStyle::Asterisk,
+            hard_break: HardBreak::Backslash,
+            autolinks: AutolinkStyle::Preserve,
+            wrap: WrapMode::Fill,
+            wrap_algorithm: WrapAlgorithm::Greedy,
+            list_spacing: ListSpacing::Preserve,
+            blank_lines_before_heading: 1,
+            blank_lines_after_heading: 1,
+            blank_lines_around_lists: 1,
+            line_ending: LineEnding::Auto,
+            origins: BTreeMap::new(),
+        }
+    }
+}
+
+impl TryFrom<Opt> for Config {
+    type Error = Error;
+
+    /// Resolve the config for the input file that was passed on the command line
+    fn try_from(opt: Opt) -> Result<Self, Self::Error> {
+        Self::load(&opt, opt.input_file.as_deref())
+    }
+}
+
+/// Where the value of a config option came from
+#[derive(Debug, Clone)]
+pub enum Origin {
+    /// The option wasn't set, so it has its default value
+    Default,
+
+    /// An `.editorconfig` file above the input file
+    EditorConfig(PathBuf),
+
+    /// The global config file in the user's config directory
+    Global(PathBuf),
+
+    /// The closest project config file to the input file
+    Project(PathBuf),
+
+    /// The config file that was passed on the command line
+    CommandLine(PathBuf),
+
+    /// A command line argument
+    Argument(&'static str),
+
+    /// A preset that was selected by one of the other origins
+    Preset(String),
+
+    /// An `[[overrides]]` section of a config file
+    Override {
+        /// The glob patterns of the files that the section applies to
+        files: String,
+
+        /// The config file that the section is in
+        origin: Box<Origin>,
+    },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default config")?,
+            Origin::EditorConfig(path) => write!(f, "EditorConfig file {}", path.display())?,
+            Origin::Global(path) => write!(f, "global config file {}", path.display())?,
+            Origin::Project(path) => match config_table(path) {
+                [] => write!(f, "project config file {}", path.display())?,
+                keys => write!(
+                    f,
+                    "`[{}]` table of project file {}",
+                    keys.join("."),
+                    path.display()
+                )?,
+            },
+            Origin::CommandLine(path) => write!(f, "command line config file {}", path.display())?,
+            Origin::Argument(argument) => write!(f, "command line argument `--{}`", argument)?,
+            Origin::Preset(preset) => write!(f, "`{}` preset", preset)?,
+            Origin::Override { files, origin } => {
+                write!(f, "override for `{}` in the {}", files, origin)?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// The options that were set by one source of configuration
+struct Layer {
+    /// Where the options came from
+    origin: Origin,
+
+    /// The options, keyed by their names in the config file
+    table: Table,
+
+    /// The sections of options that only apply to some files, in the order they were written
+    overrides: Vec<Override>,
+
+    /// The directory that the glob patterns of the overrides are relative to
+    root: PathBuf,
+}
+
+impl Layer {
+    /// Read the options from a config file
+    fn read(origin: Origin) -> Result<Self, Error> {
+        let path = match &origin {
+            Origin::Global(path) | Origin::Project(path) | Origin::CommandLine(path) => path,
+            _ => unreachable!("only config files are read"),
+        };
+        let contents = fs::read_to_string(path)?;
+        let root = path
+            .canonicalize()?
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf);
+        let invalid = |message: String| ConfigError::InvalidFile {
+            origin: origin.clone(),
+            message,
+        };
+
+        let mut table: Table =
+            toml::from_str(&contents).map_err(|error| invalid(error.to_string()))?;
+
+        // Files that belong to other tools keep the config in a table of their own
+        for key in config_table(path) {
+            table = match table.remove(*key) {
+                Some(Value::Table(inner)) => inner,
+                Some(_) => return Err(invalid(format!("`{}` is not a table", key)).into()),
+                None => Table::new(),
+            };
+        }
+        let overrides = match table.remove("overrides") {
+            Some(overrides) => overrides
+                .try_into::<Vec<OverrideSection>>()
+                .map_err(|error| invalid(error.to_string()))?
+                .into_iter()
+                .map(|section| Override::new(section, &origin))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        // The overrides are checked even if they don't apply to the file that is formatted, so a
+        // mistake in them isn't silently ignored
+        let sources: Vec<(Origin, Table)> = std::iter::once((origin.clone(), table.clone()))
+            .chain(
+                overrides
+                    .iter()
+                    .map(|section: &Override| (section.origin.clone(), section.options.clone())),
+            )
+            .collect();
+        check_sources(&sources)?;
+
+        Ok(Self {
+            origin,
+            table,
+            overrides,
+            root,
+        })
+    }
+}
+
+/// An `[[overrides]]` section, as it is written in a config file
+#[derive(Deserialize)]
+struct OverrideSection {
+    /// The glob patterns of the files that the options apply to
+    files: Vec<String>,
+
+    /// The options, keyed by their names in the config file
+    #[serde(flatten)]
+    options: Table,
+}
+
+/// Options that only apply to the files that match some glob patterns
+///
+/// Patterns that contain a `/` are matched against the path of a file relative to the directory
+/// of the config file, and other patterns are matched against the name of the file, wherever it
+/// is. For example, `CHANGELOG.md` matches every changelog, while `docs/api/**` only matches the
+/// files in the `docs/api` directory next to the config file.
+struct Override {
+    /// Where the options came from
+    origin: Origin,
+
+    /// The options, keyed by their names in the config file
+    options: Table,
+
+    /// The patterns that are matched against relative paths
+    paths: GlobSet,
+
+    /// The patterns that are matched against file names
+    names: GlobSet,
+}
+
+impl Override {
+    /// Compile the glob patterns of an `[[overrides]]` section
+    fn new(section: OverrideSection, origin: &Origin) -> Result<Self, ConfigError> {
+        let mut paths = GlobSetBuilder::new();
+        let mut names = GlobSetBuilder::new();
+
+        for pattern in &section.files {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|error| ConfigError::InvalidFile {
+                    origin: origin.clone(),
+                    message: error.to_string(),
+                })?;
+
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        // Sets of valid globs always compile
+        Ok(Self {
+            origin: Origin::Override {
+                files: section.files.join(", "),
+                origin: Box::new(origin.clone()),
+            },
+            options: section.options,
+            paths: paths.build().expect("the globs are valid"),
+            names: names.build().expect("the globs are valid"),
+        })
+    }
+
+    /// Determine whether the options apply to a file
+    ///
+    /// The `root` is the directory that relative paths are resolved from.
+    fn matches(&self, root: &Path, file: &Path) -> bool {
+        let relative = file.strip_prefix(root).unwrap_or(file);
+        let name_matches = file
+            .file_name()
+            .is_some_and(|name| self.names.is_match(name));
+        name_matches || self.paths.is_match(relative)
+    }
+}
+
+/// Check that every set of options only has valid options, and return the preset they select
+///
+/// Every unknown key is reported at once, since misspelled options would silently fall back to
+/// their default values otherwise. Each set of options is deserialized on its own, so that errors
+/// point at the right file. The preset of the last set of options that has one is returned.
+fn check_sources(sources: &[(Origin, Table)]) -> Result<Option<Preset>, ConfigError> {
+    let keys = option_keys();
+    let mut unknown = Vec::new();
+    for (origin, table) in sources {
+        for key in table.keys().filter(|key| !keys.contains(key)) {
+            let message = match suggestion(key, &keys) {
+                Some(suggestion) => format!("is not an option, did you mean `{}`?", suggestion),
+                None => "is not an option".to_owned(),
+            };
+            unknown.push(Violation {
+                key: key.to_string(),
+                origin: origin.clone(),
+                message,
+            });
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(ConfigError::Invalid {
+            violations: Violations(unknown),
+        });
+    }
+
+    let mut preset = None;
+    for (origin, table) in sources {
+        match Value::Table(table.clone()).try_into::<Config>() {
+            Ok(config) => preset = config.preset.or(preset),
+            Err(error) => {
+                return Err(ConfigError::InvalidFile {
+                    origin: origin.clone(),
+                    message: error.to_string(),
+                })
+            }
         }
     }
+    Ok(preset)
+}
+
+/// Get the keys of every option that can be set in a config file
+fn option_keys() -> Vec<String> {
+    let mut schema = schema_for!(Config);
+    schema.schema.object().properties.keys().cloned().collect()
+}
+
+/// Find the option that a misspelled key was most likely meant to be
+///
+/// Keys that are too different from every option don't get a suggestion.
+fn suggestion<'a>(key: &str, keys: &'a [String]) -> Option<&'a str> {
+    keys.iter()
+        .map(|option| (strsim::levenshtein(key, option), option))
+        .filter(|(distance, option)| *distance <= 3 && *distance < option.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, option)| option.as_str())
+}
+
+/// Get the path of the global config file
+///
+/// This is `$XDG_CONFIG_HOME/mdfmt/config.toml`, where `$XDG_CONFIG_HOME` falls back to
+/// `$HOME/.config` if it isn't set.
+fn global_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("mdfmt").join("config.toml"))
+}
+
+/// Find the project config file that is closest to a directory
+///
+/// This looks in the directory itself, followed by each of its ancestors. Files that belong to
+/// other tools, such as `Cargo.toml`, are skipped unless they have a table for our config.
+fn project_config_path(start: &Path) -> Option<PathBuf> {
+    let start = start.canonicalize().ok()?;
+    start.ancestors().find_map(|dir| {
+        PROJECT_CONFIG_FILES
+            .iter()
+            .map(|(name, keys)| (dir.join(name), keys))
+            .find(|(path, keys)| path.is_file() && (keys.is_empty() || has_table(path, keys)))
+            .map(|(path, _)| path)
+    })
 }
 
-impl From<Opt> for Config {
-    fn from(opt: Opt) -> Self {
-        if let Some(config_file) = opt.config_file {
-            // TODO(afnan) implement this
-            panic!("Config file deserialization hasn't been implemented yet");
+/// Get the keys of the table that holds the config inside of a config file
+///
+/// This is empty if the whole file is the config.
+fn config_table(path: &Path) -> &'static [&'static str] {
+    let name = path.file_name().and_then(|name| name.to_str());
+    PROJECT_CONFIG_FILES
+        .iter()
+        .find(|(file, _)| Some(*file) == name)
+        .map_or(&[], |(_, keys)| keys)
+}
+
+/// Determine whether a TOML file has a table at the given keys
+///
+/// Files that can't be read or parsed don't have any tables.
+fn has_table(path: &Path, keys: &[&str]) -> bool {
+    let mut value = match fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| toml::from_str::<Value>(&contents).ok())
+    {
+        Some(value) => value,
+        None => return false,
+    };
+
+    for key in keys {
+        value = match value.get(*key) {
+            Some(inner) => inner.clone(),
+            None => return false,
+        };
+    }
+    value.is_table()
+}
+
+impl Config {
+    /// Resolve the config for a file from every config file that applies to it
+    ///
+    /// Config files are layered on top of each other, so an option in one file overrides the same
+    /// option in the files before it. From lowest to highest precedence, these are the
+    /// `.editorconfig` files above the input file, the global config file, the project config
+    /// file that is closest to the input file, and the config file that was passed on the command
+    /// line. If there is no input file, the project config file is looked up from the current
+    /// directory.
+    pub fn load(opt: &Opt, input_file: Option<&Path>) -> Result<Self, Error> {
+        let mut layers = Vec::new();
+
+        let input_file = match input_file {
+            Some(input_file) => Some(input_file.canonicalize()?),
+            None => None,
+        };
+
+        if let Some(input_file) = &input_file {
+            for (path, table) in editorconfig::options(input_file)? {
+                layers.push(Layer {
+                    root: path.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+                    origin: Origin::EditorConfig(path),
+                    table,
+                    overrides: Vec::new(),
+                });
+            }
+        }
+
+        if let Some(path) = global_config_path().filter(|path| path.is_file()) {
+            layers.push(Layer::read(Origin::Global(path))?);
+        }
+
+        let start = match &input_file {
+            Some(input_file) => input_file.parent().map(Path::to_path_buf),
+            None => None,
+        };
+        let start = start.unwrap_or_else(|| ".".into());
+        if let Some(path) = project_config_path(&start) {
+            layers.push(Layer::read(Origin::Project(path))?);
+        }
+
+        if let Some(path) = &opt.config_file {
+            layers.push(Layer::read(Origin::CommandLine(path.to_path_buf()))?);
+        }
+
+        if let Some(preset) = &opt.preset {
+            let mut table = Table::new();
+            table.insert("preset".to_owned(), Value::String(preset.to_string()));
+            layers.push(Layer {
+                origin: Origin::Argument("preset"),
+                table,
+                overrides: Vec::new(),
+                root: PathBuf::new(),
+            });
+        }
+        Ok(Self::from_layers(layers, input_file.as_deref())?)
+    }
+
+    /// Build a config out of layers of options
+    ///
+    /// Each layer overrides the options of the layers before it, and any option that isn't set by
+    /// a layer keeps its default value. The overrides of a layer that match the input file are
+    /// applied right after the layer itself. If a preset is selected, its options replace the
+    /// default values, so every layer still takes precedence over them. The resulting config is
+    /// validated.
+    fn from_layers(layers: Vec<Layer>, input_file: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut sources = Vec::new();
+
+        for layer in layers {
+            sources.push((layer.origin, layer.table));
+
+            let root = layer.root;
+            for section in layer.overrides {
+                if input_file.is_some_and(|file| section.matches(&root, file)) {
+                    sources.push((section.origin, section.options));
+                }
+            }
+        }
+
+        let preset = check_sources(&sources)?;
+
+        if let Some(preset) = preset {
+            sources.insert(0, (Origin::Preset(preset.to_string()), preset.options()));
+        }
+
+        let mut merged = Table::new();
+        let mut origins = BTreeMap::new();
+        for (origin, table) in sources {
+            for (key, value) in table {
+                origins.insert(key.clone(), origin.clone());
+                merged.insert(key, value);
+            }
+        }
+
+        let config = Self {
+            origins,
+            ..Value::Table(merged)
+                .try_into()
+                .expect("every layer was already deserialized on its own")
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Get a JSON Schema that describes config files
+    ///
+    /// The schema is generated from the options of the config, and also describes the
+    /// `[[overrides]]` sections, which can contain any option along with the `files` that the
+    /// section applies to.
+    pub fn json_schema() -> Result<String, Error> {
+        let mut schema = schema_for!(Config);
+        schema.schema.metadata().title = Some("mdfmt config".to_owned());
+        let options = schema.schema.object().properties.clone();
+
+        let mut section = schemars::schema::SchemaObject::default();
+        section.metadata().description =
+            Some("Options that only apply to the files that match some glob patterns".to_owned());
+        section.object().properties = options;
+        section.object().properties.insert(
+            "files".to_owned(),
+            serde_json::from_value(serde_json::json!({
+                "description": "The glob patterns of the files that the options apply to",
+                "type": "array",
+                "items": { "type": "string" }
+            }))?,
+        );
+        section.object().required.insert("files".to_owned());
+        section.object().additional_properties = Some(Box::new(Schema::Bool(false)));
+
+        let mut overrides = schemars::schema::SchemaObject::default();
+        overrides.metadata().description =
+            Some("Sections of options that only apply to some files".to_owned());
+        overrides.array().items = Some(Schema::Object(section).into());
+        schema
+            .schema
+            .object()
+            .properties
+            .insert("overrides".to_owned(), Schema::Object(overrides));
+
+        Ok(serde_json::to_string_pretty(&schema)?)
+    }
+
+    /// Get where the value of an option came from
+    pub fn origin(&self, key: &str) -> &Origin {
+        self.origins.get(key).unwrap_or(&Origin::Default)
+    }
+
+    /// Serialize the config to TOML, noting where each option came from
+    ///
+    /// Every option is written out, including the ones that have their default value, and is
+    /// followed by a comment with its origin.
+    pub fn to_annotated_toml(&self) -> Result<String, Error> {
+        let mut annotated = String::new();
+
+        for line in toml::to_string(self)?.lines() {
+            annotated.push_str(line);
+
+            if let Some((key, _)) = line.split_once(" = ") {
+                annotated.push_str(&format!(" # {}", self.origin(key)));
+            }
+            annotated.push('\n');
+        }
+        Ok(annotated)
+    }
+
+    /// Check that the config options are within range and work with each other
+    ///
+    /// Every violation is reported at once, so that a config file can be fixed in one go.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut violations = Vec::new();
+        let mut violation = |key: &str, message: String| {
+            violations.push(Violation {
+                key: key.to_owned(),
+                origin: self.origin(key).clone(),
+                message,
+            });
+        };
+
+        if self.line_width < MIN_LINE_WIDTH {
+            violation(
+                "line-width",
+                format!(
+                    "must be at least {}, but it is {}",
+                    MIN_LINE_WIDTH, self.line_width
+                ),
+            );
+        }
+
+        if self.indent_width >= self.line_width {
+            violation(
+                "indent-width",
+                format!(
+                    "must be less than the line width ({}), but it is {}",
+                    self.line_width, self.indent_width
+                ),
+            );
+        }
+
+        if self.tab_width == 0 || self.tab_width >= self.line_width {
+            violation(
+                "tab-width",
+                format!(
+                    "must be between 1 and the line width ({}), but it is {}",
+                    self.line_width, self.tab_width
+                ),
+            );
+        }
+
+        if self.thematic_break_width > self.line_width {
+            violation(
+                "thematic-break-width",
+                format!(
+                    "must not be more than the line width ({}), but it is {}",
+                    self.line_width, self.thematic_break_width
+                ),
+            );
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid {
+                violations: Violations(violations),
+            })
+        }
+    }
+
+    /// Get the symbol that denotes a bullet list at the given nesting level
+    ///
+    /// Top level lists have a nesting level of `0`.
+    pub fn bullet(&self, level: usize) -> &ListDelimiter {
+        if self.list_delims.is_empty() {
+            &self.list_delim
         } else {
-            Self { ..Self::default() }
+            &self.list_delims[level % self.list_delims.len()]
         }
     }
+
+    /// Get the string used to render a thematic break
+    ///
+    /// This resolves the configured symbol and width into the literal rule that should be
+    /// written to the output file. `available` is the number of columns that are left on the line
+    /// after its prefix, which is how wide a rule that spans the whole line is.
+    pub fn thematic_break_rule(&self, available: usize) -> String {
+        let width = match self.thematic_break_width {
+            0 => available,
+            w => w,
+        };
+        // Markdown requires at least three symbols for a thematic break
+        self.thematic_break.to_string().repeat(width.max(3))
+    }
+}
+
+/// The valid characters for indenting nested content.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum IndentStyle {
+    /// Indent with spaces
+    #[serde(rename = "space")]
+    Space,
+
+    /// Indent with tabs
+    #[serde(rename = "tab")]
+    Tab,
 }
 
 /// The valid symbols that can denote a markdown list.
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum ListDelimiter {
     /// The "*" symbol
+    #[serde(rename = "*")]
     Asterisk,
 
     /// The "-" symbol
+    #[serde(rename = "-")]
     Dash,
+
+    /// The "+" symbol
+    #[serde(rename = "+")]
+    Plus,
 }
 
 impl fmt::Display for ListDelimiter {
@@ -113,7 +930,356 @@ impl fmt::Display for ListDelimiter {
         match self {
             ListDelimiter::Dash => write!(f, "-")?,
             ListDelimiter::Asterisk => write!(f, "*")?,
+            ListDelimiter::Plus => write!(f, "+")?,
+        };
+        Ok(())
+    }
+}
+
+/// The valid symbols that can denote a thematic break.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ThematicBreak {
+    /// The "-" symbol
+    #[serde(rename = "-")]
+    Dash,
+
+    /// The "*" symbol
+    #[serde(rename = "*")]
+    Asterisk,
+
+    /// The "_" symbol
+    #[serde(rename = "_")]
+    Underscore,
+}
+
+impl fmt::Display for ThematicBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThematicBreak::Dash => write!(f, "-")?,
+            ThematicBreak::Asterisk => write!(f, "*")?,
+            ThematicBreak::Underscore => write!(f, "_")?,
+        };
+        Ok(())
+    }
+}
+
+/// The valid symbols that can mark emphasis.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum EmphasisStyle {
+    /// The "*" symbol
+    #[serde(rename = "*")]
+    Asterisk,
+
+    /// The "_" symbol
+    #[serde(rename = "_")]
+    Underscore,
+}
+
+/// The valid styles for hard line breaks.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum HardBreak {
+    /// A backslash at the end of the line
+    #[serde(rename = "backslash")]
+    Backslash,
+
+    /// Two trailing spaces at the end of the line
+    #[serde(rename = "two-spaces")]
+    TwoSpaces,
+}
+
+impl fmt::Display for HardBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HardBreak::Backslash => write!(f, "\\")?,
+            HardBreak::TwoSpaces => write!(f, "  ")?,
+        };
+        Ok(())
+    }
+}
+
+/// The valid styles for autolinks.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum AutolinkStyle {
+    /// Keep autolinks the way they were written in the source document
+    #[serde(rename = "preserve")]
+    Preserve,
+
+    /// Wrap bare URLs in angle brackets
+    #[serde(rename = "angle-brackets")]
+    AngleBrackets,
+}
+
+/// The valid modes for wrapping paragraphs.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum WrapMode {
+    /// Fill each line with as many words as possible
+    #[serde(rename = "fill")]
+    Fill,
+
+    /// Start each sentence on its own line (also known as semantic line breaks)
+    #[serde(rename = "sentence")]
+    Sentence,
+
+    /// Keep the line breaks that were written in the source document
+    #[serde(rename = "preserve")]
+    Preserve,
+
+    /// Join each paragraph onto a single line
+    #[serde(rename = "never")]
+    Never,
+}
+
+/// The valid algorithms for wrapping lines.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum WrapAlgorithm {
+    /// Put as many words on each line as possible
+    #[serde(rename = "greedy")]
+    Greedy,
+
+    /// Minimize the raggedness of the whole paragraph
+    #[serde(rename = "optimal")]
+    Optimal,
+}
+
+/// The valid ways to space the items of a list.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ListSpacing {
+    /// Keep the spacing that the list was written with
+    #[serde(rename = "preserve")]
+    Preserve,
+
+    /// Never put blank lines between list items
+    #[serde(rename = "tight")]
+    Tight,
+
+    /// Always put blank lines between list items
+    #[serde(rename = "loose")]
+    Loose,
+}
+
+/// The valid line endings for the output file.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum LineEnding {
+    /// Line feeds (`\n`), which are used on Unix-like platforms
+    #[serde(rename = "lf")]
+    Lf,
+
+    /// Carriage returns followed by line feeds (`\r\n`), which are used on Windows
+    #[serde(rename = "crlf")]
+    Crlf,
+
+    /// The line ending of the platform that mdfmt runs on
+    #[serde(rename = "native")]
+    Native,
+
+    /// The line ending used by the first line of the input file
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+/// The valid presets, which each set a coherent group of options.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Preset {
+    /// The style of the examples in the CommonMark spec, which only relies on core CommonMark
+    #[serde(rename = "commonmark-spec")]
+    CommonmarkSpec,
+
+    /// The style that is common on GitHub
+    #[serde(rename = "github")]
+    Github,
+
+    /// Output that is as close as possible to what Prettier writes with its default options
+    #[serde(rename = "prettier-compatible")]
+    PrettierCompatible,
+
+    /// The default options of mdfmt
+    #[serde(rename = "mdfmt-default")]
+    MdfmtDefault,
+}
+
+impl Preset {
+    /// Get the options that the preset sets, keyed by their names in the config file
+    fn options(self) -> Table {
+        let options = match self {
+            Preset::CommonmarkSpec => {
+                r#"
+                list-delim = "-"
+                thematic-break = "*"
+                hard-break = "backslash"
+                autolinks = "angle-brackets"
+                "#
+            }
+            Preset::Github => {
+                r#"
+                list-delim = "-"
+                indent-width = 2
+                thematic-break = "-"
+                hard-break = "backslash"
+                "#
+            }
+            Preset::PrettierCompatible => {
+                r#"
+                line-width = 80
+                list-delim = "-"
+                indent-width = 2
+                thematic-break = "-"
+                thematic-break-width = 3
+                emphasis = "_"
+                wrap = "preserve"
+                line-ending = "lf"
+                "#
+            }
+            Preset::MdfmtDefault => "",
+        };
+        toml::from_str(options).expect("the options of a preset are valid")
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Preset::CommonmarkSpec => write!(f, "commonmark-spec")?,
+            Preset::Github => write!(f, "github")?,
+            Preset::PrettierCompatible => write!(f, "prettier-compatible")?,
+            Preset::MdfmtDefault => write!(f, "mdfmt-default")?,
         };
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::Formatter;
+    use crate::parser::parse;
+    use comrak::Arena;
+    use std::rc::Rc;
+
+    /// Build a config out of a single config file with the given options
+    fn config(options: &str) -> Result<Config, ConfigError> {
+        let layer = Layer {
+            origin: Origin::CommandLine(PathBuf::from("mdfmt.toml")),
+            table: toml::from_str(options).unwrap(),
+            overrides: Vec::new(),
+            root: PathBuf::new(),
+        };
+        Config::from_layers(vec![layer], None)
+    }
+
+    #[test]
+    fn overrides_are_checked_even_if_they_dont_apply() {
+        let dir = env::temp_dir().join(format!("mdfmt-overrides-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mdfmt.toml");
+
+        fs::write(
+            &path,
+            "[[overrides]]\nfiles = [\"CHANGELOG.md\"]\nwarp = \"never\"\n",
+        )
+        .unwrap();
+        let error = Layer::read(Origin::CommandLine(path.clone()))
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("did you mean `wrap`?"),
+            "{}",
+            error
+        );
+
+        fs::write(
+            &path,
+            "[[overrides]]\nfiles = [\"CHANGELOG.md\"]\nline-width = \"x\"\n",
+        )
+        .unwrap();
+        assert!(Layer::read(Origin::CommandLine(path.clone())).is_err());
+
+        fs::write(
+            &path,
+            "[[overrides]]\nfiles = [\"CHANGELOG.md\"]\nwrap = \"never\"\n",
+        )
+        .unwrap();
+        assert!(Layer::read(Origin::CommandLine(path)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_preset_is_valid() {
+        for preset in &[
+            "commonmark-spec",
+            "github",
+            "prettier-compatible",
+            "mdfmt-default",
+        ] {
+            let config = config(&format!("preset = \"{}\"", preset)).unwrap();
+            assert_eq!(config.preset().unwrap().to_string(), *preset);
+        }
+    }
+
+    #[test]
+    fn prettier_compatible_preset_formats_like_prettier() {
+        let source = "Title
+=====
+
+* item with *emphasis* and __strong__
+* second item
+    * nested item
+
+***
+
+Some text
+that keeps its line breaks.
+
+1. first
+2. second
+
+- ***
+- b
+";
+
+        // The output of Prettier 3 with its default options
+        let prettier = "# Title
+
+- item with _emphasis_ and **strong**
+- second item
+  - nested item
+
+---
+
+Some text
+that keeps its line breaks.
+
+1. first
+2. second
+
+- ***
+- b
+";
+
+        let config = Rc::new(config("preset = \"prettier-compatible\"").unwrap());
+        let arena = Arena::new();
+        let root = parse(&arena, source);
+        let formatted = Formatter::new(config, root, source).format_md(root);
+        assert_eq!(formatted, prettier);
+    }
+
+    #[test]
+    fn options_take_precedence_over_the_preset() {
+        let config = config(
+            r#"
+            preset = "prettier-compatible"
+            indent-width = 4
+            emphasis = "*"
+            wrap-algorithm = "optimal"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(*config.indent_width(), 4);
+        assert_eq!(*config.emphasis(), EmphasisStyle::Asterisk);
+        assert_eq!(*config.list_delim(), ListDelimiter::Dash);
+        assert!(matches!(
+            config.origin("indent-width"),
+            Origin::CommandLine(_)
+        ));
+    }
+}
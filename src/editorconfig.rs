@@ -0,0 +1,283 @@
+//! Support for reading formatting settings from `.editorconfig` files
+//!
+//! EditorConfig files are INI files whose sections are glob patterns. The properties of every
+//! section that matches a file apply to it, and files in directories closer to the file take
+//! precedence. We only read the properties that have an equivalent config option, and map them
+//! onto the keys of our own config file.
+
+use crate::config::MIN_LINE_WIDTH;
+use globset::GlobBuilder;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+/// The name of an EditorConfig file
+const EDITORCONFIG_FILE: &str = ".editorconfig";
+
+/// Find the options that the EditorConfig files above a file set for it
+///
+/// This returns the path of each EditorConfig file that sets an option, along with the options it
+/// sets. They are ordered from the lowest to the highest precedence, so the file that is furthest
+/// away from `file` comes first. The search stops at the first file that declares `root = true`.
+pub fn options(file: &Path) -> Result<Vec<(PathBuf, Table)>, io::Error> {
+    let mut found = Vec::new();
+
+    for dir in file.ancestors().skip(1) {
+        let path = dir.join(EDITORCONFIG_FILE);
+
+        if !path.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&path)?;
+        let (root, sections) = parse(&contents);
+        let options = options_for(&sections, dir, file);
+
+        if !options.is_empty() {
+            found.push((path, options));
+        }
+
+        if root {
+            break;
+        }
+    }
+    found.reverse();
+    Ok(found)
+}
+
+/// A section of an EditorConfig file
+struct Section {
+    /// The glob pattern of the files that the section applies to
+    pattern: String,
+
+    /// The properties of the section, with lowercase names
+    properties: Vec<(String, String)>,
+}
+
+/// Parse the contents of an EditorConfig file
+///
+/// This returns whether the file is the root file, along with its sections in the order they were
+/// written. Lines that can't be parsed are skipped, like other EditorConfig implementations do.
+fn parse(contents: &str) -> (bool, Vec<Section>) {
+    let mut root = false;
+    let mut sections: Vec<Section> = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push(Section {
+                pattern: line[1..line.len() - 1].to_owned(),
+                properties: Vec::new(),
+            });
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_lowercase();
+
+            match sections.last_mut() {
+                Some(section) => section.properties.push((key, value)),
+                // Properties before the first section describe the file itself
+                None if key == "root" => root = value == "true",
+                None => (),
+            }
+        }
+    }
+    (root, sections)
+}
+
+/// Get the config options that the matching sections of an EditorConfig file set for a file
+///
+/// `dir` is the directory of the EditorConfig file. Later sections override earlier ones.
+fn options_for(sections: &[Section], dir: &Path, file: &Path) -> Table {
+    let mut options = Table::new();
+
+    for section in sections
+        .iter()
+        .filter(|section| matches(section, dir, file))
+    {
+        for (key, value) in &section.properties {
+            if let Some((option, value)) = option(key, value) {
+                options.insert(option.to_owned(), value);
+            }
+        }
+    }
+    options
+}
+
+/// Determine whether a section applies to a file
+///
+/// Patterns that contain a `/` are relative to the directory of the EditorConfig file, and other
+/// patterns match files with that name in any directory.
+fn matches(section: &Section, dir: &Path, file: &Path) -> bool {
+    let pattern = &section.pattern;
+    let matcher = match GlobBuilder::new(pattern.trim_start_matches('/'))
+        .literal_separator(true)
+        .build()
+    {
+        Ok(glob) => glob.compile_matcher(),
+        // Sections with invalid patterns don't apply to anything
+        Err(_) => return false,
+    };
+
+    if pattern.contains('/') {
+        file.strip_prefix(dir)
+            .is_ok_and(|relative| matcher.is_match(relative))
+    } else {
+        file.file_name().is_some_and(|name| matcher.is_match(name))
+    }
+}
+
+/// Map an EditorConfig property onto a config option
+///
+/// Returns the key of the config option along with its value, or `None` if the property doesn't
+/// have an equivalent option or its value isn't supported. Widths are only used if they are
+/// valid whatever the other options are, so a line width must be at least `MIN_LINE_WIDTH`, and
+/// indent and tab widths must be less than it.
+fn option(key: &str, value: &str) -> Option<(&'static str, Value)> {
+    let number = |range: Range<usize>| {
+        value
+            .parse::<usize>()
+            .ok()
+            .filter(|number| range.contains(number))
+            .map(|number| Value::Integer(number as i64))
+    };
+
+    match key {
+        "max_line_length" => Some(("line-width", number(MIN_LINE_WIDTH..i64::MAX as usize)?)),
+        "indent_size" => Some(("indent-width", number(0..MIN_LINE_WIDTH)?)),
+        "tab_width" => Some(("tab-width", number(1..MIN_LINE_WIDTH)?)),
+        "indent_style" => match value {
+            "space" | "tab" => Some(("indent-style", Value::String(value.to_owned()))),
+            _ => None,
+        },
+        "end_of_line" => match value {
+            "lf" | "crlf" => Some(("line-ending", Value::String(value.to_owned()))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Get the first section of an EditorConfig file
+    fn section(contents: &str) -> Section {
+        parse(contents).1.remove(0)
+    }
+
+    #[test]
+    fn parse_reads_root_and_sections_in_order() {
+        let (root, sections) = parse(
+            "# comment\nroot = true\n\n[*]\nIndent_Size = 2\n; comment\n[*.md]\nmax_line_length = OFF\nnot a property\n",
+        );
+
+        assert!(root);
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].pattern, "*");
+        assert_eq!(
+            sections[0].properties,
+            vec![("indent_size".to_owned(), "2".to_owned())]
+        );
+        assert_eq!(sections[1].pattern, "*.md");
+        assert_eq!(
+            sections[1].properties,
+            vec![("max_line_length".to_owned(), "off".to_owned())]
+        );
+    }
+
+    #[test]
+    fn root_is_only_read_before_the_first_section() {
+        let (root, _) = parse("[*]\nroot = true\n");
+        assert!(!root);
+    }
+
+    #[test]
+    fn patterns_without_a_slash_match_file_names_anywhere() {
+        let dir = Path::new("/project");
+        let section = section("[*.{md,markdown}]\n");
+
+        assert!(matches(&section, dir, Path::new("/project/README.md")));
+        assert!(matches(
+            &section,
+            dir,
+            Path::new("/project/docs/guide.markdown")
+        ));
+        assert!(!matches(&section, dir, Path::new("/project/notes.txt")));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_are_relative_to_the_editorconfig_file() {
+        let dir = Path::new("/project");
+
+        for pattern in &["[docs/*.md]\n", "[/docs/*.md]\n"] {
+            let section = section(pattern);
+            assert!(matches(&section, dir, Path::new("/project/docs/guide.md")));
+            assert!(!matches(
+                &section,
+                dir,
+                Path::new("/project/src/docs/guide.md")
+            ));
+            assert!(!matches(
+                &section,
+                dir,
+                Path::new("/project/docs/api/guide.md")
+            ));
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_skipped() {
+        assert_eq!(option("indent_size", "-2"), None);
+        assert_eq!(option("indent_size", "tab"), None);
+        assert_eq!(option("max_line_length", "10"), None);
+        assert_eq!(option("max_line_length", "off"), None);
+        assert_eq!(option("tab_width", "0"), None);
+        assert_eq!(option("end_of_line", "cr"), None);
+
+        assert_eq!(
+            option("max_line_length", "100"),
+            Some(("line-width", Value::Integer(100)))
+        );
+        assert_eq!(
+            option("indent_size", "2"),
+            Some(("indent-width", Value::Integer(2)))
+        );
+    }
+
+    #[test]
+    fn options_stop_at_the_root_file() {
+        let top = env::temp_dir().join(format!("mdfmt-editorconfig-{}", std::process::id()));
+        let project = top.join("project");
+        fs::create_dir_all(project.join("docs")).unwrap();
+        fs::write(top.join(EDITORCONFIG_FILE), "[*]\ntab_width = 8\n").unwrap();
+        fs::write(
+            project.join(EDITORCONFIG_FILE),
+            "root = true\n[*]\nindent_size = 2\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("docs").join(EDITORCONFIG_FILE),
+            "[*.md]\nindent_size = 3\nmax_line_length = 10\n",
+        )
+        .unwrap();
+
+        let found = options(&project.join("docs").join("guide.md")).unwrap();
+        fs::remove_dir_all(&top).unwrap();
+
+        let tables: Vec<&Table> = found.iter().map(|(_, table)| table).collect();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].get("indent-width"), Some(&Value::Integer(2)));
+        assert_eq!(tables[1].get("indent-width"), Some(&Value::Integer(3)));
+        assert!(tables.iter().all(|table| !table.contains_key("tab-width")));
+        assert!(tables.iter().all(|table| !table.contains_key("line-width")));
+    }
+}
@@ -6,11 +6,24 @@
 //! The general idea is to have each AST type be formatted separately, since they all have
 //! different logic.
 
-use crate::config::Config;
-use comrak::{arena_tree::NodeEdge, nodes::NodeValue};
+use crate::config::{
+    AutolinkStyle, Config, EmphasisStyle, IndentStyle, LineEnding, ListSpacing, WrapAlgorithm,
+    WrapMode,
+};
+use crate::parser::angle_autolinks;
+use comrak::{
+    arena_tree::NodeEdge,
+    nodes::{ListDelimType, ListType, NodeLink, NodeList, NodeValue},
+};
 use getset::Getters;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 use std::{mem::discriminant, rc::Rc, str};
+use unicode_linebreak::linebreaks;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 /// Wrapper for `println` for debug builds
 ///
@@ -35,18 +48,50 @@ type NodeRef<'a> = &'a comrak::arena_tree::Node<'a, std::cell::RefCell<comrak::n
 ///
 /// It contains a reference to a prefix, which level it was added at, and what type of node added
 /// it.
+///
+/// Container nodes (such as list items and block quotes) have two prefixes: one for the first line
+/// that is written inside of the container (for example a list marker), and a continuation prefix
+/// for every line after that.
 #[derive(Debug, Getters)]
 struct PrefixStackElement {
     /// The variant of the node associated with this prefix
     pub node_value: NodeValue,
 
-    /// The contents of the prefix
+    /// The contents of the prefix for the first line inside of the node
     pub prefix: String,
 
+    /// The contents of the prefix for every subsequent line inside of the node
+    pub continuation: String,
+
     /// Which depth this prefix was added at
     pub depth: usize,
+
+    /// Whether any lines have been written inside of the node yet
+    pub started: bool,
 }
 
+/// A piece of inline content that has been collected from the AST
+///
+/// Paragraphs are collected into a sequence of these elements before they are wrapped, so the
+/// text wrapper knows where it is allowed to break lines.
+#[derive(Debug, Clone, PartialEq)]
+enum Inline {
+    /// Text that may be broken across lines at any space
+    Text(String),
+
+    /// Text that must never be broken across lines, such as a code span or an inline HTML tag
+    Atom(String),
+
+    /// A soft line break, which is treated like a space
+    SoftBreak,
+
+    /// A hard line break, which always ends the current line
+    LineBreak,
+}
+
+/// The number of columns between tab stops when markdown is parsed
+const MARKDOWN_TAB_WIDTH: usize = 4;
+
 /// Routines to format a markdown file
 ///
 /// This struct also houses state data that is relevant to the formatter, as well as configuration
@@ -61,14 +106,27 @@ pub struct Formatter {
 
     /// An internal stack containing the prefix for a markdown element
     prefix_stack: Stack<PrefixStackElement>,
+
+    /// The URLs that were written as autolinks in angle brackets in the source document
+    ///
+    /// The parser doesn't distinguish between these and bare URLs, so we need to remember which
+    /// form was used in order to preserve it. The form of each occurrence of a URL is taken off
+    /// the front of its queue as the autolinks are formatted in document order.
+    angle_autolinks: RefCell<HashMap<String, VecDeque<bool>>>,
+
+    /// The characters that end every line of the output
+    line_ending: &'static str,
 }
 
 impl Formatter {
-    /// Create a new `Formatter`, given a formatting config
-    pub fn new(config: Rc<Config>) -> Self {
+    /// Create a new `Formatter`, given a formatting config, the root node of the source document
+    /// and its contents
+    pub fn new(config: Rc<Config>, root: NodeRef, source: &str) -> Self {
         Self {
-            config,
             prefix_stack: Vec::new(),
+            angle_autolinks: RefCell::new(angle_autolinks(root, source)),
+            line_ending: line_ending(config.line_ending(), source),
+            config,
         }
     }
 
@@ -94,32 +152,24 @@ impl Formatter {
                     // TODO create a method to get a prefix, pass the prefix to the format method
                     // Check to see whether this node allocates a new prefix. If so, add the prefix
                     // to the stack with the metadata so we know when to pop it.
-                    let prefix_candidate = self.node_prefix(node, depth);
-
-                    if let Some(prefix) = prefix_candidate.clone() {
-                        self.prefix_stack.push(PrefixStackElement {
-                            prefix,
-                            depth,
-                            node_value: node.data.borrow().value.clone(),
-                        });
+                    if let Some(prefix) = self.node_prefix(node, depth) {
+                        self.prefix_stack.push(prefix);
                     }
+                    let (prefix, continuation) = self.line_prefixes();
 
-                    // Get an (`Option`) reference to the prefix so we can pass it around
-                    let prefix_opt = match self.prefix_stack.last() {
-                        Some(p) => Some(p.prefix.as_str()),
-                        None => None,
-                    };
-
-                    if let Some(formatted_string) = self.format_node(node, prefix_opt) {
+                    if let Some(formatted_string) = self.format_node(node, &prefix, &continuation) {
                         formatted.push_str(&formatted_string);
+
+                        // Every container on the stack now has a line written inside of it, so
+                        // only their continuation prefixes are used from here on
+                        for element in self.prefix_stack.iter_mut() {
+                            element.started = true;
+                        }
                     }
                 }
                 NodeEdge::End(node) => {
                     debugln!("[END {}] {:?}", depth - 1, &node.data.borrow().value);
-                    // FIXME(afnan) try to prevent trailing newlines
-                    // only add a suffix if the next node is not trailing, otherwise we will end up
-                    // with a bunch of trailing newline characters
-                    if let Some(suffix) = node_suffix(node) {
+                    if let Some(suffix) = self.node_suffix(node, depth) {
                         formatted.push_str(&suffix);
                     }
 
@@ -138,47 +188,122 @@ impl Formatter {
                 }
             }
         }
-        // FIXME(afnan) there are still extraneous newlines that we need to remove with a final
-        // pass, which is unnecessary. We should fix where the newlines are inserted in the first
-        // place.
-        formatted.trim().to_owned()
+
+        // Lines are written with `\n` while formatting, so other line endings are only applied to
+        // the finished document
+        if self.line_ending != "\n" {
+            formatted = formatted.replace('\n', self.line_ending);
+        }
+        formatted
+    }
+
+    /// Get the prefixes for the next block that is written
+    ///
+    /// The prefix of a line is made up of the prefixes of every container that the line is nested
+    /// in. This returns the prefix for the first line of the block, followed by the prefix for
+    /// every subsequent line.
+    fn line_prefixes(&self) -> (String, String) {
+        let prefix = self
+            .prefix_stack
+            .iter()
+            .map(|element| {
+                if element.started {
+                    element.continuation.as_str()
+                } else {
+                    element.prefix.as_str()
+                }
+            })
+            .collect();
+        let continuation = self
+            .prefix_stack
+            .iter()
+            .map(|element| element.continuation.as_str())
+            .collect();
+        (prefix, continuation)
     }
 
     /// Format the contents of a node to text (if applicable)
     ///
     /// This function takes a reference to an AST node and formats a string according the the
-    /// formatting configuration options. It takes an optional prefix, which should be the current
-    /// prefix on the stack.
-    fn format_node(&self, node: NodeRef, prefix: Option<&str>) -> Option<String> {
+    /// formatting configuration options. It takes the prefix for the first line of the node and
+    /// the prefix for every subsequent line, which are built from the prefix stack.
+    fn format_node(&self, node: NodeRef, prefix: &str, continuation: &str) -> Option<String> {
         match &node.data.borrow().value {
             NodeValue::CodeBlock(node) => {
                 // This is the language you put after the backticks (if there is one specified)
                 // ex: ```c
                 let lang = String::from_utf8(node.info.clone()).unwrap();
                 let code = String::from_utf8(node.literal.clone()).unwrap();
-                Some(format!("```{}\n{}\n```", lang, code))
-            }
-            NodeValue::Link(link) => {
-                let url_str = String::from_utf8(link.url.clone()).unwrap();
-                let link_text = collect_link_title_text(node);
-                let formatted_link = format!("[{}]({})", link_text, url_str);
-                Some(formatted_link)
+                let code = code.strip_suffix('\n').unwrap_or(&code);
+
+                // The fence has to be longer than any run of its character inside of the code
+                // block. Backtick fences can't have a backtick in their info string, so those
+                // blocks are fenced with tildes instead.
+                let fence_char = if lang.contains('`') { '~' } else { '`' };
+                let fence = fence_char
+                    .to_string()
+                    .repeat(longest_run(code, fence_char).max(2) + 1);
+                let block = format!("{}{}\n{}\n{}", fence, lang, code, fence);
+                Some(indent_block(prefix, continuation, &block))
             }
             NodeValue::Paragraph => {
-                let raw_text = collect_text(node);
-                let wrapped = self.wrap_text(prefix, &raw_text);
+                let inlines = self.collect_inlines(node);
+                let wrapped = self.wrap_text(prefix, continuation, &inlines);
                 Some(wrapped)
             }
             NodeValue::Heading(h) => {
                 // This is guaranteed to never panic because there can be at most 6 levels, so we
                 // don't run the risk of an overflow or something like that.
                 let hashtags = "#".repeat(h.level.try_into().unwrap());
-                Some(hashtags + " " + &collect_text(node))
+                let mut text = self.collect_text(node);
+
+                // A trailing run of `#` would be parsed as the closing sequence of the heading
+                if text.ends_with('#') {
+                    let run_start = text.trim_end_matches('#').len();
+                    text.insert(run_start, '\\');
+                }
+                Some(format!(
+                    "{}{} {}",
+                    prefix,
+                    hashtags,
+                    escape_line_start(&text)
+                ))
             }
             NodeValue::HtmlBlock(html_block) => {
-                Some(String::from_utf8(html_block.literal.clone()).unwrap())
+                let html = String::from_utf8(html_block.literal.clone()).unwrap();
+                Some(indent_block(
+                    prefix,
+                    continuation,
+                    html.trim_end_matches('\n'),
+                ))
+            }
+            NodeValue::ThematicBreak => {
+                let available = self
+                    .config
+                    .line_width()
+                    .saturating_sub(self.prefix_width(prefix));
+                let mut rule = self.config.thematic_break_rule(available);
+
+                // A rule on the first line of a list item that is drawn with the same symbol as
+                // the item's bullet would make the whole line a thematic break, so the rule
+                // switches to a symbol that isn't in the prefix
+                let markers: String = prefix
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && *c != '>')
+                    .collect();
+                if !markers.is_empty() && rule.chars().all(|c| markers.contains(c)) {
+                    let symbol = ['-', '*', '_']
+                        .iter()
+                        .find(|symbol| !markers.contains(**symbol))
+                        .unwrap();
+                    rule = symbol.to_string().repeat(rule.len());
+                }
+                Some(format!("{}{}", prefix, rule))
+            }
+            // An empty list item is only its marker
+            NodeValue::Item(_) if node.first_child().is_none() => {
+                Some(prefix.trim_end().to_owned())
             }
-            NodeValue::ThematicBreak => Some("---".to_owned()),
             _ => None,
         }
     }
@@ -190,179 +315,1575 @@ impl Formatter {
     /// need to supply the `depth` of the node in the syntax tree.
     ///
     /// Most nodes do not have a prefix, which is indicated by the `None` type.
+    ///
+    /// Prefixes are relative to the prefix of the parent container, so the indentation of nested
+    /// content is derived from the actual chain of ancestors and the widths of their markers.
     // TODO(afnan) We should maybe add a newline for a node that is a child of a paragraph, TBD
     // pending how we handle text wrapping for links.
-    fn node_prefix(&self, node: NodeRef, depth: usize) -> Option<String> {
-        match &node.data.borrow().value {
+    fn node_prefix(&self, node: NodeRef, depth: usize) -> Option<PrefixStackElement> {
+        let node_value = node.data.borrow().value.clone();
+
+        let (prefix, continuation) = match &node_value {
+            NodeValue::BlockQuote => ("> ".to_owned(), "> ".to_owned()),
             NodeValue::Item(_) => {
-                // We calculate the depth of a list by subtracting one for the document (which
-                // doesn't contribute to the nested depth of a list), and recognize that the depth
-                // of the elements inside of a list are doubled because we have a `List` which we
-                // don't take text from, and then the `Item`, which we process here
-                let indent = " ".repeat(self.indent_offset((depth / 2) - 1));
-                let res = format!("{}{} ", indent, self.config.list_delim().to_string());
-                Some(res)
+                // The content of a list item has to be aligned with the first character after
+                // the list marker, otherwise it won't belong to the item
+                let marker = self.list_marker(node);
+                let continuation = match self.config.indent_style() {
+                    IndentStyle::Space => " ".repeat(display_width(&marker)),
+                    IndentStyle::Tab => self.tab_continuation(&marker),
+                };
+                (marker, continuation)
             }
-            _ => None,
+            NodeValue::List(_) => {
+                // Nested lists are indented by the indent width, measured from the start of the
+                // parent item's marker. Content can be indented at most three spaces past the
+                // parent's content before it turns into an indented code block.
+                let parent = node.parent()?;
+
+                // Tabs already indent nested lists to the next tab stop
+                if *self.config.indent_style() == IndentStyle::Tab {
+                    return None;
+                }
+
+                if let NodeValue::Item(_) = parent.data.borrow().value {
+                    let marker_width = display_width(&self.list_marker(parent));
+                    let extra = self.indent_offset(1).saturating_sub(marker_width).min(3);
+                    (" ".repeat(extra), " ".repeat(extra))
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        };
+
+        Some(PrefixStackElement {
+            node_value,
+            prefix,
+            continuation,
+            depth,
+            started: false,
+        })
+    }
+
+    /// Get the tabs that align the content of a list item with the text after its marker
+    ///
+    /// Markdown always puts tab stops four columns apart, so this is independent of the tab width
+    /// that is used to measure lines. The content has to start within three columns after the
+    /// marker, otherwise it would turn into an indented code block.
+    fn tab_continuation(&self, marker: &str) -> String {
+        let (prefix, continuation) = self.line_prefixes();
+        let content_column = expanded_width(&prefix, MARKDOWN_TAB_WIDTH) + display_width(marker);
+        let mut column = expanded_width(&continuation, MARKDOWN_TAB_WIDTH);
+        let mut tabs = String::new();
+
+        while column < content_column {
+            column = (column / MARKDOWN_TAB_WIDTH + 1) * MARKDOWN_TAB_WIDTH;
+            tabs.push('\t');
         }
+        tabs
     }
 
-    /// Wrap text according to the config options
+    /// Get the width of a line prefix, which may contain tabs
+    fn prefix_width(&self, prefix: &str) -> usize {
+        expanded_width(prefix, *self.config.tab_width())
+    }
+
+    /// Get the marker for a list item, including the space that follows it
     ///
-    /// You may supply an optional prefix that will be used to determine the leading indent width
-    /// for the whole text block.
+    /// Bullet lists use the configured delimiter for their nesting level, and ordered lists are
+    /// numbered sequentially from the start of the list.
+    fn list_marker(&self, item: NodeRef) -> String {
+        // The number of lists that this item's list is nested in
+        let level = item
+            .ancestors()
+            .filter(|node| matches!(node.data.borrow().value, NodeValue::List(_)))
+            .count()
+            .saturating_sub(1);
+
+        let (parent, list) =
+            match item
+                .parent()
+                .and_then(|parent| match parent.data.borrow().value {
+                    NodeValue::List(list) => Some((parent, list)),
+                    _ => None,
+                }) {
+                Some(list) => list,
+                None => return format!("{} ", self.config.bullet(level)),
+            };
+        let symbol = self.list_symbol(parent, &list, level);
+
+        match list.list_type {
+            ListType::Bullet => format!("{} ", symbol),
+            ListType::Ordered => {
+                // `preceding_siblings` includes the item itself
+                let number = list.start + item.preceding_siblings().count() - 1;
+                format!("{}{} ", number, symbol)
+            }
+        }
+    }
+
+    /// Get the symbol that marks the items of a list
     ///
-    /// For example "  * " will ensure that for every line after the first line of text, we lead
-    /// with four spaces.
-    fn wrap_text(&self, prefix: Option<&str>, text: &str) -> String {
-        // Because we don't want to split up links, we tokenize links first, and then tokenize the
-        // remaining strings by words
-        //
-        let tokenized: Vec<&str> = text.split(' ').collect();
+    /// This is the bullet for the nesting level of a bullet list, or the delimiter after the
+    /// numbers of an ordered list. A list that directly follows another list of the same type
+    /// would be merged into it if they used the same symbol, so it switches to another one.
+    fn list_symbol(&self, node: NodeRef, list: &NodeList, level: usize) -> String {
+        let symbol = match list.list_type {
+            ListType::Bullet => self.config.bullet(level).to_string(),
+            ListType::Ordered => match list.delimiter {
+                ListDelimType::Period => ".".to_owned(),
+                ListDelimType::Paren => ")".to_owned(),
+            },
+        };
 
-        // The resulting vector, in which each string is a separate line
-        let mut res_vec: Vec<String> = Vec::new();
+        let previous = match node.previous_sibling() {
+            Some(previous) => previous,
+            None => return symbol,
+        };
+        let previous_list = match previous.data.borrow().value {
+            NodeValue::List(previous_list) if previous_list.list_type == list.list_type => {
+                previous_list
+            }
+            _ => return symbol,
+        };
 
-        // Closure to create a new string, with the capacity of the line width (DRY)
-        let new_string = || String::with_capacity(*self.config.line_width());
+        if self.list_symbol(previous, &previous_list, level) != symbol {
+            return symbol;
+        }
+        match symbol.as_str() {
+            "." => ")",
+            ")" => ".",
+            "*" => "-",
+            _ => "*",
+        }
+        .to_owned()
+    }
 
-        // We already know the max line width, so we can reserve the memory ahead of time
-        let mut current_line = new_string();
+    /// Wrap text according to the config options
+    ///
+    /// The `prefix` is written at the start of the first line, and the `continuation` prefix is
+    /// written at the start of every subsequent line. These determine the leading indent width for
+    /// the whole text block.
+    ///
+    /// For example, the prefix "* " and the continuation "  " ensure that every line after the
+    /// first line of a list item is aligned with the text of the item.
+    ///
+    /// Each segment between hard line breaks is wrapped separately, the configured hard break
+    /// marker is appended to the end of the segment, and the next segment restarts on a new line
+    /// with the continuation prefix.
+    fn wrap_text(&self, prefix: &str, continuation: &str, inlines: &[Inline]) -> String {
+        let line_break = format!("{}\n", self.config.hard_break());
 
-        // Calculate the padding for the text "box" on the left side
-        let space_prefix = match prefix {
-            Some(p) => Some(" ".repeat(p.len())),
-            None => None,
+        inlines
+            .split(|inline| *inline == Inline::LineBreak)
+            .enumerate()
+            .map(|(index, segment)| {
+                // Only the first segment starts with the actual prefix, every segment after a
+                // hard break is a continuation of the same block
+                let first_prefix = if index == 0 { prefix } else { continuation };
+                self.wrap_segment(first_prefix, continuation, segment)
+            })
+            .collect::<Vec<String>>()
+            .join(&line_break)
+    }
+
+    /// Wrap a segment of text that doesn't contain any hard line breaks
+    ///
+    /// The `prefix` is pushed onto the first line of the segment, and every subsequent line is
+    /// led by the `continuation` prefix. How the text is wrapped depends on the configured wrap
+    /// mode.
+    fn wrap_segment(&self, prefix: &str, continuation: &str, inlines: &[Inline]) -> String {
+        let words = tokenize(inlines);
+        let line_width = *self.config.line_width();
+
+        // Every wrap mode splits the words into groups that each start on a new line, and then
+        // wraps the lines of each group to some width (if there is one)
+        let (groups, width) = match self.config.wrap() {
+            WrapMode::Fill => (vec![&words[..]], Some(line_width)),
+            // Sentences that are too long for a single line are wrapped like regular text
+            WrapMode::Sentence => (split_sentences(&words), Some(line_width)),
+            // The authored line breaks are kept, and lines are never wrapped any further
+            WrapMode::Preserve => (split_soft_breaks(&words), None),
+            WrapMode::Never => (vec![&words[..]], None),
         };
 
+        groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| {
+                let first_prefix = if index == 0 { prefix } else { continuation };
+
+                match (width, self.config.wrap_algorithm()) {
+                    (Some(width), WrapAlgorithm::Optimal) => {
+                        self.fit_words(first_prefix, continuation, group, width)
+                    }
+                    (width, _) => self.fill_words(
+                        first_prefix,
+                        continuation,
+                        group,
+                        width.unwrap_or(usize::MAX),
+                    ),
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Fill lines with as many words as possible
+    ///
+    /// The `prefix` is pushed onto the first line, and every subsequent line is led by the
+    /// `continuation` prefix. Lines are filled up to `width` columns, which includes the prefix.
+    ///
+    /// A new line is only started in between two words, so a word that doesn't fit on a line by
+    /// itself overflows it instead of leaving behind a line that only contains the prefix. Spaces
+    /// are only added in between words on the same line, so lines never end with whitespace.
+    ///
+    /// Words that can't start a line (see `can_break_before`) are kept on the previous line, even
+    /// if that makes it overflow.
+    fn fill_words(&self, prefix: &str, continuation: &str, words: &[Word], width: usize) -> String {
+        // The resulting vector, in which each string is a separate line
+        let mut lines: Vec<String> = Vec::new();
+
         // Push the actual prefix only onto the first line. All subsequent lines will have a space
         // offset equal to the offset created by the prefix.
-        if let Some(p) = prefix {
-            current_line.push_str(p);
-        }
+        let mut current_line = prefix.to_owned();
+        let mut current_width = self.prefix_width(&current_line);
 
-        // Loop through each word, either pushing to the current line or creating a new line based
-        // on whether the word would fit on the current line. This performs a text wrap in O(n)
-        // time.
-        for (index, word) in tokenized.iter().enumerate() {
-            let space_left = if *self.config.line_width() < current_line.len() {
-                0
-            } else {
-                *self.config.line_width() - current_line.len()
-            };
+        for (index, word) in words.iter().enumerate() {
+            let word_width = display_width(&word.text);
+            let separator_width = word.space_before as usize;
+
+            if index > 0 {
+                let fits = current_width + separator_width + word_width <= width;
 
-            // We check a few different lengths here so that we can prevent accidentally adding
-            // trailing whitespaces to the end of a line. In order to determine whether we want to
-            // add whitespace to the end of the current line, we want to check and see if the next
-            // word will fit on the line. If the next word does not fit on the line, then don't add
-            // a trailing character.
-            if word.len() > space_left {
-                res_vec.push(current_line);
-                current_line = new_string();
-
-                if let Some(p) = space_prefix.as_ref() {
-                    current_line.push_str(&p);
+                if fits || !can_break_before(words, index) {
+                    if word.space_before {
+                        current_line.push(' ');
+                    }
+                    current_line.push_str(&word.text);
+                    current_width += separator_width + word_width;
+                    continue;
                 }
+                lines.push(current_line);
+                current_line = continuation.to_owned();
+                current_width = self.prefix_width(&current_line);
             }
-            current_line.push_str(word);
-            let next_index = index + 1;
 
-            if next_index < tokenized.len() {
-                // Guard against overflows when the current line's length goes over the configured
-                // line width
-                let space_left = if current_line.len() > *self.config.line_width() {
-                    0
+            // Words that start a line need to be escaped so they aren't interpreted as the start
+            // of a new block
+            let escaped = escape_line_start(&word.text);
+            current_width += display_width(&escaped);
+            current_line.push_str(&escaped);
+        }
+        lines.push(current_line);
+        join_lines(&lines)
+    }
+
+    /// Break words into lines that are as even as possible
+    ///
+    /// This is a minimum raggedness algorithm in the spirit of Knuth and Plass. Every line except
+    /// for the last one is penalized by the square of the space that is left over at the end of
+    /// it, and the line breaks with the lowest total penalty are found with dynamic programming.
+    /// This is O(n^2) in the worst case, but we stop extending a line as soon as it overflows, so
+    /// it is closer to linear for regular text.
+    ///
+    /// The `prefix` is pushed onto the first line, and every subsequent line is led by the
+    /// `continuation` prefix. Lines are fit within `width` columns, which includes the prefix.
+    fn fit_words(&self, prefix: &str, continuation: &str, words: &[Word], width: usize) -> String {
+        let first_width = width.saturating_sub(self.prefix_width(prefix));
+        let rest_width = width.saturating_sub(self.prefix_width(continuation));
+
+        // Words that start a line may need to be escaped, which makes them wider
+        let widths: Vec<usize> = words.iter().map(|w| display_width(&w.text)).collect();
+        let start_widths: Vec<usize> = words
+            .iter()
+            .map(|w| display_width(&escape_line_start(&w.text)))
+            .collect();
+
+        // Some words can't start a line, so they stay on the line before them even if it
+        // overflows
+        let sticky: Vec<bool> = (0..words.len())
+            .map(|index| index > 0 && !can_break_before(words, index))
+            .collect();
+
+        // `penalty[i]` is the lowest total penalty for laying out `words[i..]` when `words[i]`
+        // starts a line, and `breaks[i]` is the start of the line that follows it
+        let word_count = words.len();
+        let mut penalty = vec![f64::INFINITY; word_count + 1];
+        let mut breaks = vec![word_count; word_count + 1];
+        penalty[word_count] = 0.0;
+
+        for start in (0..word_count).rev() {
+            let available = if start == 0 { first_width } else { rest_width };
+            let mut line_width = start_widths[start];
+
+            for end in start + 1..=word_count {
+                if end > start + 1 {
+                    let word = &words[end - 1];
+                    line_width += widths[end - 1] + word.space_before as usize;
+
+                    // A word that is too long for a line on its own is allowed to overflow, but
+                    // we never add more words to a line that overflows
+                    if line_width > available && !sticky[end - 1] {
+                        break;
+                    }
+                }
+
+                if end < word_count && sticky[end] {
+                    continue;
+                }
+
+                // The last line doesn't need to be filled
+                let line_penalty = if end == word_count {
+                    0.0
                 } else {
-                    self.config.line_width() - current_line.len()
+                    (available.saturating_sub(line_width) as f64).powi(2)
+                };
+                let total = line_penalty + penalty[end];
+
+                if total < penalty[start] {
+                    penalty[start] = total;
+                    breaks[start] = end;
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut start = 0;
+
+        // A paragraph without any words still gets its prefix
+        if word_count == 0 {
+            lines.push(prefix.to_owned());
+        }
+
+        while start < word_count {
+            let end = breaks[start];
+            let line_prefix = if start == 0 { prefix } else { continuation };
+            let mut line = line_prefix.to_owned();
+            line.push_str(&escape_line_start(&words[start].text));
+
+            for word in &words[start + 1..end] {
+                if word.space_before {
+                    line.push(' ');
+                }
+                line.push_str(&word.text);
+            }
+            lines.push(line);
+            start = end;
+        }
+        join_lines(&lines)
+    }
+
+    /// Recursively extract the inline elements from a node (if they exist)
+    ///
+    /// This function takes a reference to an existing vector so it can recursively extend the
+    /// output.
+    fn collect_text_helper(&self, node: NodeRef, output: &mut Vec<Inline>) {
+        match node.data.borrow().value {
+            NodeValue::Link(ref link) => {
+                // A `!` right before a link would turn it into an image
+                if let Some(Inline::Text(text)) = output.last_mut() {
+                    if text.ends_with('!') {
+                        text.pop();
+                        text.push_str("\\!");
+                    }
+                }
+                self.collect_link(node, link, output)
+            }
+            NodeValue::Image(ref link) => {
+                output.push(Inline::Text("!".to_owned()));
+                self.collect_link(node, link, output);
+            }
+            NodeValue::Text(ref literal) => {
+                let text = String::from_utf8_lossy(literal);
+                output.push(Inline::Text(escape_text(&text)))
+            }
+            // Breaking a code span across lines would change its contents, so it is atomic
+            NodeValue::Code(ref literal) => {
+                let code = String::from_utf8_lossy(literal);
+                output.push(Inline::Atom(format_code_span(&code)))
+            }
+            // Inline HTML is kept verbatim. Whitespace (including newlines) inside of a tag is
+            // insignificant, so it is normalized to spaces and the tag is never broken up.
+            NodeValue::HtmlInline(ref literal) => {
+                let html = String::from_utf8_lossy(literal).replace('\n', " ");
+                output.push(Inline::Atom(html))
+            }
+            NodeValue::Emph => {
+                // Underscores can't emphasize part of a word, so those always use asterisks
+                let before = match output.last() {
+                    Some(Inline::Text(text)) | Some(Inline::Atom(text)) => text.chars().last(),
+                    _ => None,
+                };
+                let after = node
+                    .next_sibling()
+                    .and_then(|next| match next.data.borrow().value {
+                        NodeValue::Text(ref literal) => {
+                            str::from_utf8(literal).ok()?.chars().next()
+                        }
+                        _ => None,
+                    });
+                let intraword = before.is_some_and(char::is_alphanumeric)
+                    || after.is_some_and(char::is_alphanumeric);
+
+                let delim = match self.config.emphasis() {
+                    EmphasisStyle::Underscore if !intraword => "_",
+                    _ => "*",
                 };
+                self.wrap_children(node, delim, output)
+            }
+            NodeValue::Strong => self.wrap_children(node, "**", output),
+            NodeValue::Strikethrough => self.wrap_children(node, "~~", output),
+            NodeValue::Superscript => self.wrap_children(node, "^", output),
+            NodeValue::SoftBreak => output.push(Inline::SoftBreak),
+            NodeValue::LineBreak => output.push(Inline::LineBreak),
+            // The checkbox of a task list item can't be broken up, and is always followed by a
+            // space
+            NodeValue::TaskItem(checked) => {
+                output.push(Inline::Atom(if checked { "[x]" } else { "[ ]" }.to_owned()));
+                output.push(Inline::Text(" ".to_owned()));
+            }
+            _ => {
+                for child in node.children() {
+                    self.collect_text_helper(child, output);
+                }
+            }
+        };
+    }
+
+    /// Collect the inline elements of a node's children, surrounded by an inline delimiter
+    ///
+    /// This is used for inline elements such as emphasis, where the children of the node need to
+    /// be wrapped with the delimiter that denotes the element, for example `**` for strong
+    /// emphasis.
+    fn wrap_children(&self, node: NodeRef, delim: &str, output: &mut Vec<Inline>) {
+        output.push(Inline::Text(delim.to_owned()));
+
+        for child in node.children() {
+            self.collect_text_helper(child, output);
+        }
+        output.push(Inline::Text(delim.to_owned()));
+    }
+
+    /// Collect the inline elements for a link
+    ///
+    /// The link text is collected like any other inline text, so it can be wrapped. The
+    /// destination and title are atomic, so they always stay attached to the end of the link
+    /// text.
+    ///
+    /// Autolinks are kept in the form they were written in (either a bare URL or wrapped in angle
+    /// brackets), unless the config asks for bare URLs to be wrapped in angle brackets.
+    fn collect_link(&self, node: NodeRef, link: &NodeLink, output: &mut Vec<Inline>) {
+        let url = String::from_utf8_lossy(&link.url);
+        let text = link_literal_text(node);
+
+        if link.title.is_empty() {
+            if let Some(text) = text {
+                let angle_style = *self.config.autolinks() == AutolinkStyle::AngleBrackets;
+
+                // The text of a URL or email autolink is the link itself (minus the `mailto:`
+                // scheme for emails). These can always be written in angle brackets.
+                if url == text || url == format!("mailto:{}", text) {
+                    let angled = self
+                        .angle_autolinks
+                        .borrow_mut()
+                        .get_mut(url.as_ref())
+                        .and_then(VecDeque::pop_front)
+                        .unwrap_or(false);
 
-                if tokenized[next_index].len() <= space_left {
-                    current_line.push_str(" ");
+                    if angle_style || angled {
+                        output.push(Inline::Atom(format!("<{}>", text)));
+                    } else {
+                        output.push(Inline::Atom(text));
+                    }
+                    return;
+                }
+
+                // Bare `www.` links are given an implicit `http` scheme. These aren't valid
+                // autolinks in CommonMark, so when we want portable output we fall through and
+                // spell out the destination as a regular link.
+                if url == format!("http://{}", text) && !angle_style {
+                    output.push(Inline::Atom(text));
+                    return;
                 }
             }
         }
-        // Push the last line
-        res_vec.push(current_line);
-        res_vec.join("\n")
+        output.push(Inline::Text("[".to_owned()));
+
+        for child in node.children() {
+            self.collect_text_helper(child, output);
+        }
+        let title = String::from_utf8_lossy(&link.title);
+        output.push(Inline::Atom(format!(
+            "]({})",
+            format_destination(&url, &title)
+        )));
+    }
+
+    /// Recursively get all of the inline elements from a node
+    ///
+    /// _NOTE: This is a wrapper for the actual recursive method_
+    fn collect_inlines(&self, node: NodeRef) -> Vec<Inline> {
+        let mut inlines: Vec<Inline> = Vec::new();
+        self.collect_text_helper(node, &mut inlines);
+        inlines
+    }
+
+    /// Recursively get all of the text from a node
+    ///
+    /// This is meant for elements that can't be wrapped, such as headings, so every kind of line
+    /// break is flattened to a space.
+    fn collect_text(&self, node: NodeRef) -> String {
+        inlines_to_string(&self.collect_inlines(node))
+    }
+    /// Determine the suffix of a node
+    ///
+    /// This returns the suffix of a node, if it is applicable. This should be used with the `End`
+    /// variant of a node, along with the depth of the node.
+    ///
+    /// Blocks that write lines end their last line with a newline. If a block is followed by a
+    /// sibling that has to be set apart from it, a blank line is added as well. The blank line
+    /// only has the prefixes of the containers around it, such as `>` for a block quote.
+    fn node_suffix(&self, node: NodeRef, depth: usize) -> Option<String> {
+        // The `document` node is a sentinel node that doesn't have any information, and inlines
+        // are written by the blocks that contain them
+        let parent = node.parent()?;
+
+        if !node.data.borrow().value.block() {
+            return None;
+        }
+        let mut suffix = String::new();
+
+        if writes_lines(node) {
+            suffix.push('\n');
+        }
+
+        if let Some(next) = node.next_sibling() {
+            let blank_line: String = self
+                .prefix_stack
+                .iter()
+                .filter(|element| element.depth < depth)
+                .map(|element| element.continuation.as_str())
+                .collect();
+            let blank_line = format!("{}\n", blank_line.trim_end());
+            suffix.push_str(&blank_line.repeat(self.blank_lines(node, parent, next)));
+        }
+
+        if suffix.is_empty() {
+            None
+        } else {
+            Some(suffix)
+        }
+    }
+
+    /// Determine how many blank lines separate a block from its next sibling
+    ///
+    /// Blocks are separated by one blank line, except for the items of a tight list and the
+    /// blocks inside of those items. Even then, a blank line is used if the blocks would be
+    /// merged together when the document is parsed again. The spacing around top level headings
+    /// and lists can be changed in the config.
+    fn blank_lines(&self, node: NodeRef, parent: NodeRef, next: NodeRef) -> usize {
+        match &parent.data.borrow().value {
+            NodeValue::Document => {
+                let mut blank_lines = 1;
+
+                if matches!(next.data.borrow().value, NodeValue::Heading(_)) {
+                    blank_lines = *self.config.blank_lines_before_heading();
+                } else if matches!(node.data.borrow().value, NodeValue::Heading(_)) {
+                    blank_lines = *self.config.blank_lines_after_heading();
+                }
+
+                let is_list =
+                    |node: NodeRef| matches!(node.data.borrow().value, NodeValue::List(_));
+                if is_list(node) || is_list(next) {
+                    blank_lines = blank_lines.max(*self.config.blank_lines_around_lists());
+                }
+
+                // A list would take in the next block as a lazy continuation line, and an HTML
+                // block only ends at a blank line
+                let needs_separation = is_list(node)
+                    || matches!(node.data.borrow().value, NodeValue::HtmlBlock(_))
+                    || would_merge(node, next);
+                if needs_separation {
+                    blank_lines.max(1)
+                } else {
+                    blank_lines
+                }
+            }
+            NodeValue::List(list) => self.is_loose(list) as usize,
+            NodeValue::Item(_) => {
+                let loose = match parent.parent().map(|list| list.data.borrow().value.clone()) {
+                    Some(NodeValue::List(list)) => self.is_loose(&list),
+                    _ => true,
+                };
+                (loose || would_merge(node, next)) as usize
+            }
+            _ => 1,
+        }
+    }
+
+    /// Determine whether the items of a list are separated by blank lines
+    ///
+    /// Lists keep the spacing they were written with unless the config normalizes it.
+    fn is_loose(&self, list: &NodeList) -> bool {
+        match self.config.list_spacing() {
+            ListSpacing::Preserve => !list.tight,
+            ListSpacing::Tight => false,
+            ListSpacing::Loose => true,
+        }
+    }
+}
+
+/// Get the characters that end a line for the configured line ending
+///
+/// The `auto` line ending uses whatever ends the first line of the source document, and falls
+/// back to `\n` if the document only has a single line.
+fn line_ending(line_ending: &LineEnding, source: &str) -> &'static str {
+    match line_ending {
+        LineEnding::Lf => "\n",
+        LineEnding::Crlf => "\r\n",
+        LineEnding::Native if cfg!(windows) => "\r\n",
+        LineEnding::Native => "\n",
+        LineEnding::Auto => match source.find('\n') {
+            Some(index) if source[..index].ends_with('\r') => "\r\n",
+            _ => "\n",
+        },
     }
 }
 
-/// Recursively extract the inline text from a node (if it exists)
+/// Determine whether a block writes its own lines
 ///
-/// This function takes a reference to an existing unicode vector so it can recursively extend
-/// the output.
-fn collect_text_helper(node: NodeRef, output: &mut Vec<u8>) {
+/// These are the leaf blocks, which have to end their last line. Containers rely on the blocks
+/// inside of them, except for empty list items, which are written as a bare marker.
+fn writes_lines(node: NodeRef) -> bool {
     match node.data.borrow().value {
-        // Links should handle their own text so we ignore any text inside of a link node
-        NodeValue::Link(_) => (),
-        NodeValue::Text(ref literal) | NodeValue::Code(ref literal) => {
-            output.extend_from_slice(literal)
+        NodeValue::Paragraph
+        | NodeValue::HtmlBlock(_)
+        | NodeValue::CodeBlock(_)
+        | NodeValue::Heading(_)
+        | NodeValue::ThematicBreak => true,
+        NodeValue::Item(_) => node.first_child().is_none(),
+        _ => false,
+    }
+}
+
+/// Determine whether a block would be merged into the paragraph before it
+///
+/// Some blocks can't interrupt a paragraph, so they would become part of it if there was no blank
+/// line in between. A thematic break made of dashes would even turn the paragraph into a setext
+/// heading.
+fn would_merge(node: NodeRef, next: NodeRef) -> bool {
+    if !matches!(node.data.borrow().value, NodeValue::Paragraph) {
+        return false;
+    }
+
+    match &next.data.borrow().value {
+        NodeValue::Paragraph | NodeValue::HtmlBlock(_) | NodeValue::ThematicBreak => true,
+        // Only ordered lists that start at 1 can interrupt a paragraph
+        NodeValue::List(list) => list.list_type == ListType::Ordered && list.start != 1,
+        _ => false,
+    }
+}
+
+/// Get the literal text of a link if the link only contains plain text
+///
+/// This is used to detect autolinks, whose text is the same as their destination.
+fn link_literal_text(node: NodeRef) -> Option<String> {
+    let mut children = node.children();
+
+    match (children.next(), children.next()) {
+        (Some(child), None) => match child.data.borrow().value {
+            NodeValue::Text(ref literal) => Some(String::from_utf8_lossy(literal).into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Format the destination and optional title of a link
+///
+/// Destinations that contain spaces or unbalanced parentheses are wrapped in angle brackets, and
+/// titles are always quoted.
+fn format_destination(url: &str, title: &str) -> String {
+    let depth = url.chars().try_fold(0usize, |depth, c| match c {
+        '(' => Some(depth + 1),
+        ')' => depth.checked_sub(1),
+        _ => Some(depth),
+    });
+    let balanced = depth == Some(0);
+
+    let destination = if url.is_empty() || !balanced || url.contains(|c: char| c.is_whitespace()) {
+        format!("<{}>", url.replace('<', "\\<").replace('>', "\\>"))
+    } else {
+        url.to_owned()
+    };
+
+    if title.is_empty() {
+        destination
+    } else {
+        let title = title.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("{} \"{}\"", destination, title)
+    }
+}
+
+/// Get the width of a string when it is displayed in a terminal
+///
+/// This is different from the number of bytes or characters in the string. Wide characters (such
+/// as CJK characters and most emoji) take up two columns, while combining characters take up
+/// none. Every grapheme cluster is capped at two columns so that sequences such as ZWJ emoji are
+/// measured the way they are displayed.
+fn display_width(text: &str) -> usize {
+    text.graphemes(true)
+        .map(|grapheme| grapheme.width().min(2))
+        .sum()
+}
+
+/// Get the display width of a string in which tabs advance to the next multiple of `tab_width`
+fn expanded_width(text: &str, tab_width: usize) -> usize {
+    text.split('\t')
+        .enumerate()
+        .fold(0, |column, (index, part)| {
+            let column = if index > 0 && tab_width > 0 {
+                (column / tab_width + 1) * tab_width
+            } else {
+                column
+            };
+            column + display_width(part)
+        })
+}
+
+/// A word that the text wrapper can place
+///
+/// Words can't be broken any further, so the text wrapper can only start a new line in between
+/// two words.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Word {
+    /// The text of the word
+    text: String,
+
+    /// Whether the word is separated from the previous word by a space
+    ///
+    /// This is false for words that were split at a break opportunity that doesn't have a space,
+    /// such as in between two CJK characters.
+    space_before: bool,
+
+    /// Whether the word follows a soft line break in the source document
+    soft_break: bool,
+}
+
+impl Word {
+    /// Create an empty word that is separated from the previous word by a space
+    fn spaced() -> Self {
+        Self {
+            space_before: true,
+            ..Self::default()
         }
-        NodeValue::SoftBreak => output.push(b' '),
-        NodeValue::LineBreak => output.push(b'\n'),
-        _ => {
-            for child in node.children() {
-                collect_text_helper(child, output);
+    }
+}
+
+/// Split a sequence of inline elements into the words that the text wrapper can place
+///
+/// Lines may only be broken at spaces inside of text, at soft breaks, and at the break
+/// opportunities that the Unicode line breaking algorithm finds in between two CJK
+/// characters. Everything else, including atoms and inline delimiters, is glued onto the word
+/// that it is adjacent to.
+fn tokenize(inlines: &[Inline]) -> Vec<Word> {
+    let mut words = vec![Word::default()];
+
+    for (index, inline) in inlines.iter().enumerate() {
+        match inline {
+            Inline::Text(text) => {
+                for (piece_index, piece) in text.split(' ').enumerate() {
+                    if piece_index > 0 {
+                        words.push(Word::spaced());
+                    }
+
+                    for (chunk_index, chunk) in split_spaceless(piece).into_iter().enumerate() {
+                        if chunk_index > 0 {
+                            words.push(Word::default());
+                        }
+                        words.last_mut().unwrap().text.push_str(chunk);
+                    }
+                }
+            }
+            Inline::Atom(atom) => words.last_mut().unwrap().text.push_str(atom),
+            Inline::SoftBreak => {
+                // Languages such as Chinese and Japanese don't use spaces in between words, so a
+                // soft break in between two of their characters must not be rejoined with a space
+                let before = words.last().and_then(|word| word.text.chars().last());
+                let after = match inlines.get(index + 1) {
+                    Some(Inline::Text(text)) => text.chars().next(),
+                    _ => None,
+                };
+                let space_before =
+                    !(before.is_some_and(is_spaceless) && after.is_some_and(is_spaceless));
+                words.push(Word {
+                    text: String::new(),
+                    space_before,
+                    soft_break: true,
+                });
             }
+            Inline::LineBreak => (),
         }
-    };
+    }
+
+    // Runs of spaces (and soft breaks next to spaces) leave behind empty words. These are
+    // collapsed into the word that follows them so we never emit doubled spaces.
+    let mut collapsed: Vec<Word> = Vec::with_capacity(words.len());
+    let mut pending = Word::default();
+
+    for word in words {
+        if word.text.is_empty() {
+            pending.space_before |= word.space_before;
+            pending.soft_break |= word.soft_break;
+        } else {
+            collapsed.push(Word {
+                space_before: (word.space_before || pending.space_before) && !collapsed.is_empty(),
+                soft_break: word.soft_break || pending.soft_break,
+                ..word
+            });
+            pending = Word::default();
+        }
+    }
+    collapsed
 }
 
-/// Collects the link for a text
+/// Join wrapped lines, making sure that none of them end with trailing whitespace
 ///
-/// Collect the title text for a link. This is the part of the link that is in the brackets. For
-/// example, if we had the markown snippet `[here is a link](example.com)`, then the title text
-/// would be "here is a link".
+/// Lines can only end with whitespace if they don't contain anything besides their prefix, for
+/// example a list item prefix for an empty paragraph.
+fn join_lines(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|line| line.trim_end_matches(' '))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Split a sequence of words into sentences
 ///
-/// We have to keep this method separate from the collect text method in order to make sure that
-/// the other elements don't accidentally recursively pick up the text in a link title and
-/// duplicate it elsewhere in the document.
-fn collect_link_title_text(node: NodeRef) -> String {
-    let mut unicode: Vec<u8> = Vec::new();
+/// A sentence ends with a word that ends in terminal punctuation (ignoring closing quotes,
+/// brackets and emphasis delimiters), as long as the next word doesn't look like the sentence
+/// is continuing. This is a heuristic, so common abbreviations such as "e.g." are skipped.
+fn split_sentences(words: &[Word]) -> Vec<&[Word]> {
+    /// Abbreviations that end with a period but rarely end a sentence
+    const ABBREVIATIONS: &[&str] = &["e.g.", "i.e.", "cf.", "vs.", "Mr.", "Mrs.", "Ms.", "Dr."];
+
+    /// Characters that can close a sentence after its terminal punctuation
+    const CLOSING: &[char] = &[')', ']', '"', '\'', '’', '”', '*', '_'];
+
+    let mut sentences = Vec::new();
+    let mut sentence_start = 0;
 
-    for child in node.children() {
-        collect_text_helper(child, &mut unicode);
+    for index in 1..words.len() {
+        let previous = words[index - 1].text.trim_end_matches(CLOSING);
+        let next = &words[index];
+
+        if ABBREVIATIONS.contains(&previous) {
+            continue;
+        }
+
+        let ends_sentence = if !can_break_before(words, index) {
+            false
+        } else if previous.ends_with(&['。', '！', '？'][..]) {
+            // CJK punctuation ends a sentence even if the next sentence isn't separated by a
+            // space
+            true
+        } else if previous.ends_with(&['.', '!', '?'][..]) && next.space_before {
+            // The next sentence should start with something other than a lowercase letter
+            next.text
+                .chars()
+                .find(|c| c.is_alphanumeric())
+                .is_some_and(|c| !c.is_lowercase())
+        } else {
+            false
+        };
+
+        if ends_sentence {
+            sentences.push(&words[sentence_start..index]);
+            sentence_start = index;
+        }
     }
-    String::from_utf8(unicode).unwrap_or_else(|_| "".to_owned())
+    sentences.push(&words[sentence_start..]);
+    sentences
 }
 
-/// Recursively get all of the text from a node
+/// Split a sequence of words into the lines they were written on in the source document
+fn split_soft_breaks(words: &[Word]) -> Vec<&[Word]> {
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+
+    for (index, word) in words.iter().enumerate().skip(1) {
+        if word.soft_break {
+            lines.push(&words[line_start..index]);
+            line_start = index;
+        }
+    }
+    lines.push(&words[line_start..]);
+    lines
+}
+
+/// Determine whether a character belongs to a script that is written without spaces
 ///
-/// _NOTE: This is a wrapper for the actual recursive method_
-fn collect_text(node: NodeRef) -> String {
-    let mut unicode: Vec<u8> = Vec::new();
-    collect_text_helper(node, &mut unicode);
-    String::from_utf8(unicode).unwrap_or_else(|_| "".to_owned())
+/// These are the Han ideographs, Hiragana, Katakana, Bopomofo and full-width punctuation of
+/// Chinese and Japanese. Hangul is wide as well, but Korean puts spaces in between its words, so
+/// it is wrapped like any other text.
+fn is_spaceless(c: char) -> bool {
+    matches!(c,
+        '\u{2E80}'..='\u{2FDF}'
+        | '\u{3000}'..='\u{303F}'
+        | '\u{3040}'..='\u{30FF}'
+        | '\u{3100}'..='\u{312F}'
+        | '\u{3190}'..='\u{31FF}'
+        | '\u{3200}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FE30}'..='\u{FE4F}'
+        | '\u{FF00}'..='\u{FF9F}'
+        | '\u{FFE0}'..='\u{FFE6}'
+        | '\u{20000}'..='\u{3FFFF}'
+    )
 }
 
-/// Determine the suffix of a node
+/// Split a piece of text that doesn't contain any spaces at its line break opportunities
 ///
-/// This returns the suffix of a node, if it is applicable. This should be used with the `End`
-/// variant of a node.
-fn node_suffix(node: NodeRef) -> Option<String> {
-    let node_variant = &node.data.borrow().value;
-
-    // if we have an element that is ending a direct descendant of the document node and a block,
-    // then give it an extra newline
-    if let Some(parent) = node.parent() {
-        let parent_type = discriminant(&parent.data.borrow().value);
-        let document_type = discriminant(&NodeValue::Document);
-
-        // These are high-level blocks that should be separated by newlines. We special case lists
-        // because they already have an extra newline from their internal `Paragraph` blocks.
-        if parent_type == document_type && node_variant.block() {
-            return match node_variant {
-                NodeValue::List(_) => Some("\n".to_owned()),
-                _ => Some("\n\n".to_owned()),
-            };
+/// We use the Unicode line breaking algorithm (UAX #14) to find break opportunities, but only
+/// take the ones that are in between two characters of a script that is written without spaces.
+/// This lets us wrap Chinese and Japanese text without breaking up things like hyphenated words,
+/// Korean words or inline delimiters that would change how the text renders.
+fn split_spaceless(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0;
+
+    for (index, _) in linebreaks(text) {
+        if index == 0 || index >= text.len() {
+            continue;
         }
-        // Otherwise we just want to end the element with a single newline
-        return match node_variant {
-            NodeValue::Paragraph => Some("\n".to_owned()),
-            _ => None,
+        let before = text[..index].chars().last();
+        let after = text[index..].chars().next();
+
+        if before.is_some_and(is_spaceless) && after.is_some_and(is_spaceless) {
+            chunks.push(&text[chunk_start..index]);
+            chunk_start = index;
+        }
+    }
+    chunks.push(&text[chunk_start..]);
+    chunks
+}
+
+/// Format the literal contents of an inline code span
+///
+/// The code span is fenced with one more backtick than the longest run of backticks inside of
+/// the literal so that it can't be closed early. If the literal starts or ends with a backtick it
+/// is padded with a space, which the parser strips back off.
+fn format_code_span(code: &str) -> String {
+    let fence = "`".repeat(longest_run(code, '`') + 1);
+    let padding = if code.starts_with('`') || code.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}{}{}{}", fence, padding, code, padding, fence)
+}
+
+/// Escape the characters of a text literal that would otherwise be interpreted as inline markup
+///
+/// This escaping is context aware, so characters are only escaped when they could actually
+/// change how the text is parsed. For example, underscores inside of a word (`snake_case`) can't
+/// create emphasis, so they are left alone.
+///
+/// Text that could create a new block when it ends up at the start of a line is handled
+/// separately by `escape_line_start`, since we only know where lines start after wrapping.
+fn escape_text(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut escaped = String::with_capacity(text.len());
+
+    for (index, &c) in chars.iter().enumerate() {
+        let prev = if index > 0 {
+            chars.get(index - 1)
+        } else {
+            None
         };
+        let next = chars.get(index + 1);
+
+        let needs_escape = match c {
+            '\\' | '`' | '*' | '[' | ']' | '~' | '^' => true,
+            // Smart punctuation turns straight quotes into curly ones, so straight quotes in the
+            // text must have been escaped
+            '"' | '\'' => true,
+            // Runs of dashes and periods turn into dashes and ellipses
+            '-' => next == Some(&'-'),
+            '.' => next == Some(&'.') && chars.get(index + 2) == Some(&'.'),
+            // Underscores can only open or close emphasis at the boundary of a word
+            '_' => {
+                !(prev.is_some_and(|p| p.is_alphanumeric())
+                    && next.is_some_and(|n| n.is_alphanumeric()))
+            }
+            // Angle brackets can start inline HTML or an autolink. The text that follows the end
+            // of the literal is unknown, so a trailing angle bracket is always escaped.
+            '<' => next.is_none_or(|n| n.is_ascii_alphabetic() || "/!?".contains(*n)),
+            // Ampersands only need to be escaped if they look like an entity
+            '&' => is_entity(&chars[index..]),
+            _ => false,
+        };
+
+        if needs_escape {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Determine whether a slice of characters starting with `&` would be parsed as an entity
+///
+/// This is a conservative check, so anything that resembles a named or numeric entity is
+/// considered an entity.
+fn is_entity(chars: &[char]) -> bool {
+    let body: String = chars
+        .iter()
+        .skip(1)
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '#')
+        .collect();
+    !body.is_empty() && chars.get(body.chars().count() + 1) == Some(&';')
+}
+
+/// Escape a word that starts a line so it can't be interpreted as a block marker
+///
+/// After text wrapping, any word can end up at the start of a line, so a word such as `1.` or
+/// `#` could accidentally create a list item or heading. This escapes the first character of the
+/// word if that is possible.
+fn escape_line_start(word: &str) -> Cow<'_, str> {
+    let is_all = |c: char| word.chars().all(|w| w == c);
+
+    // ATX headings, block quotes, bullet lists and setext heading underlines/thematic breaks
+    let block_marker = (is_all('#') && word.len() <= 6)
+        || word.starts_with('>')
+        || word == "+"
+        || is_all('-')
+        || is_all('=');
+
+    if !word.is_empty() && block_marker {
+        return Cow::Owned(format!("\\{}", word));
+    }
+
+    // Ordered lists are made up of up to nine digits followed by either a `.` or a `)`
+    let digits = word.chars().take_while(char::is_ascii_digit).count();
+
+    if digits > 0 && digits <= 9 && digits + 1 == word.len() && word.ends_with(&['.', ')'][..]) {
+        return Cow::Owned(format!("{}\\{}", &word[..digits], &word[digits..]));
+    }
+    Cow::Borrowed(word)
+}
+
+/// Determine whether a line can be broken right before a word
+///
+/// Words that would start an HTML block can't start a line. Neither can a word that is separated
+/// by a space from a previous word, when both sides of the space are in a script that is written
+/// without spaces, since the line break would be joined back together without the space when the
+/// document is formatted again.
+fn can_break_before(words: &[Word], index: usize) -> bool {
+    let word = &words[index];
+    let before = words[index - 1].text.chars().last();
+    let after = word.text.chars().next();
+    let joined_without_space =
+        word.space_before && before.is_some_and(is_spaceless) && after.is_some_and(is_spaceless);
+
+    !starts_html_block(&word.text) && !joined_without_space
+}
+
+/// Determine whether a word would start an HTML block if it was at the start of a line
+///
+/// These are the HTML blocks that can interrupt a paragraph (types 1 to 6 in the CommonMark
+/// spec), so an inline HTML tag that is wrapped onto a new line would turn the rest of the
+/// paragraph into raw HTML.
+fn starts_html_block(word: &str) -> bool {
+    /// The tags that start a type 6 HTML block
+    const BLOCK_TAGS: &[&str] = &[
+        "address",
+        "article",
+        "aside",
+        "base",
+        "basefont",
+        "blockquote",
+        "body",
+        "caption",
+        "center",
+        "col",
+        "colgroup",
+        "dd",
+        "details",
+        "dialog",
+        "dir",
+        "div",
+        "dl",
+        "dt",
+        "fieldset",
+        "figcaption",
+        "figure",
+        "footer",
+        "form",
+        "frame",
+        "frameset",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "head",
+        "header",
+        "hr",
+        "html",
+        "iframe",
+        "legend",
+        "li",
+        "link",
+        "main",
+        "menu",
+        "menuitem",
+        "nav",
+        "noframes",
+        "ol",
+        "optgroup",
+        "option",
+        "p",
+        "param",
+        "section",
+        "source",
+        "summary",
+        "table",
+        "tbody",
+        "td",
+        "tfoot",
+        "th",
+        "thead",
+        "title",
+        "tr",
+        "track",
+        "ul",
+    ];
+
+    /// The tags that start a type 1 HTML block
+    const RAW_TAGS: &[&str] = &["script", "pre", "style", "textarea"];
+
+    let rest = match word.strip_prefix('<') {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    // Comments, processing instructions, declarations and CDATA sections
+    if rest.starts_with("!--")
+        || rest.starts_with('?')
+        || rest.starts_with("![CDATA[")
+        || rest
+            .strip_prefix('!')
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+    {
+        return true;
+    }
+
+    let closing = rest.starts_with('/');
+    let name_start = rest.strip_prefix('/').unwrap_or(rest);
+    let name: String = name_start
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let after = &name_start[name.len()..];
+
+    if !closing && RAW_TAGS.contains(&name.as_str()) {
+        return after.is_empty() || after.starts_with(|c: char| c == '>' || c.is_whitespace());
+    }
+    BLOCK_TAGS.contains(&name.as_str())
+        && (after.is_empty() || after.starts_with('>') || after.starts_with("/>"))
+}
+
+/// Flatten a sequence of inline elements onto a single line
+fn inlines_to_string(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) | Inline::Atom(text) => text.as_str(),
+            Inline::SoftBreak | Inline::LineBreak => " ",
+        })
+        .collect()
+}
+
+/// Indent a block of preformatted text
+///
+/// The first line is led by the prefix, and every subsequent line is led by the continuation
+/// prefix. Blank lines only get the continuation prefix without any trailing whitespace, so a
+/// blank line inside of a block quote still belongs to the quote.
+fn indent_block(prefix: &str, continuation: &str, text: &str) -> String {
+    text.split('\n')
+        .enumerate()
+        .map(|(index, line)| {
+            let line_prefix = if index == 0 { prefix } else { continuation };
+
+            if line.is_empty() {
+                line_prefix.trim_end().to_owned()
+            } else {
+                format!("{}{}", line_prefix, line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Get the length of the longest run of a character in some text
+fn longest_run(text: &str, target: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+
+    for c in text.chars() {
+        if c == target {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, parse};
+    use comrak::{format_html, Arena};
+    use proptest::prelude::*;
+
+    /// Format a document with the options from a TOML snippet
+    fn format(source: &str, options: &str) -> String {
+        let config: Config = toml::from_str(options).unwrap();
+        let arena = Arena::new();
+        let root = parse(&arena, source);
+        Formatter::new(Rc::new(config), root, source).format_md(root)
+    }
+
+    /// Render a document to HTML, treating every run of whitespace (such as a soft line break)
+    /// like a single space
+    fn render(source: &str) -> String {
+        let arena = Arena::new();
+        let root = parse(&arena, source);
+        let mut html = Vec::new();
+        format_html(root, &parser::options(), &mut html).unwrap();
+        String::from_utf8(html)
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    /// Check that a formatted document parses to the same document as its source
+    fn assert_round_trip(source: &str, options: &str) {
+        let formatted = format(source, options);
+        assert_eq!(
+            render(&formatted),
+            render(source),
+            "formatted as:\n{}",
+            formatted
+        );
+    }
+
+    #[test]
+    fn fixtures_reparse_to_the_same_document() {
+        let fixtures = [
+            include_str!("../test/data/test.md"),
+            include_str!("../test/data/test_link_only.md"),
+            include_str!("../test/data/gfx_notes.md"),
+            include_str!("../test/data/multilingual.md"),
+            include_str!("../docs/README.md"),
+            include_str!("../docs/manpage.md"),
+        ];
+
+        for source in &fixtures {
+            assert_round_trip(source, "line-width = 80");
+            assert_round_trip(source, "line-width = 20");
+        }
+    }
+
+    #[test]
+    fn escaped_text_reparses_to_the_same_document() {
+        let source = r#"Run \--in-place with \"quotes\", \'quotes\' and dots\... or \<https://a.com>.
+
+Wow\![link](x) and \*stars\* and \_underscores\_ and 1\. and \# and \&amp; and a\<
+
+- [ ] task
+- [x] done
+"#;
+        assert_round_trip(source, "line-width = 80");
+        assert_round_trip(source, "line-width = 20");
+    }
+
+    #[test]
+    fn multilingual_lines_fit_their_display_width() {
+        let source = include_str!("../test/data/multilingual.md");
+        let formatted = format(source, "line-width = 80");
+
+        for line in formatted.lines() {
+            assert!(display_width(line) <= 80, "line is too wide: {:?}", line);
+        }
+
+        // Emoji and Hangul are two columns wide, so these lines wrap earlier than their
+        // character count suggests
+        assert!(formatted.contains("lot of them\n🦀 🦀 🦀 should wrap"));
+        assert!(formatted.contains("줄을 바꿀 수 있습니다. 한글\n글자는"));
+        assert_eq!(format(&formatted, "line-width = 80"), formatted);
+    }
+
+    #[test]
+    fn cjk_text_breaks_in_between_characters() {
+        let source = include_str!("../test/data/cjk.md");
+        let formatted = format(source, "line-width = 80");
+
+        for line in formatted.lines() {
+            assert!(display_width(line) <= 80, "line is too wide: {:?}", line);
+        }
+
+        // The Chinese paragraph doesn't have any spaces, but is still wrapped, and the lines are
+        // joined back together without adding any
+        let paragraph = formatted.split("\n\n").nth(1).unwrap();
+        assert!(paragraph.lines().count() > 1);
+        assert!(!paragraph.contains(' '));
+        assert_eq!(
+            paragraph.replace('\n', ""),
+            source.split("\n\n").nth(1).unwrap()
+        );
+        assert_eq!(format(&formatted, "line-width = 80"), formatted);
+    }
+
+    #[test]
+    fn cjk_soft_breaks_are_joined_without_a_space() {
+        let formatted = format(include_str!("../test/data/cjk.md"), "line-width = 80");
+        assert!(
+            formatted.contains("\n这一行在源文件中被软换行分开了，重新合并时不应该插入空格。\n")
+        );
+
+        // Soft breaks next to other text are still joined with a space
+        assert_eq!(
+            format("中文\nEnglish\n", "line-width = 80"),
+            "中文 English\n"
+        );
+    }
+
+    #[test]
+    fn cjk_lines_never_start_with_closing_punctuation() {
+        // Without the line breaking rules, the `。` and `、` would be the first characters that
+        // don't fit on each line
+        let source = "一二三四五六七八九十。一二三四五六七八九十、一二三四五六七八九十\n";
+        let formatted = format(source, "line-width = 20");
+
+        assert!(formatted.lines().count() > 1);
+        for line in formatted.lines() {
+            assert!(
+                !line.starts_with(&['、', '。'][..]),
+                "line starts with punctuation: {:?}",
+                line
+            );
+        }
+        assert_eq!(formatted.replace('\n', ""), source.trim_end());
+
+        let japanese = format(include_str!("../test/data/cjk.md"), "line-width = 80");
+        assert!(!japanese
+            .lines()
+            .any(|line| line.starts_with(&['、', '。', '」'][..])));
+    }
+
+    #[test]
+    fn korean_is_only_broken_at_spaces() {
+        let source =
+            "한국어 문장은 단어 사이에 공백을 사용하므로 공백에서 줄을 바꿀 수 있습니다.\n";
+        let formatted = format(source, "line-width = 20");
+
+        assert!(formatted.lines().count() > 1);
+        assert_eq!(formatted.replace('\n', " "), source.replace('\n', " "));
+    }
+
+    #[test]
+    fn rewrapping_at_another_width_keeps_the_text() {
+        let fixtures = [
+            include_str!("../test/data/multilingual.md"),
+            include_str!("../test/data/cjk.md"),
+        ];
+
+        for source in &fixtures {
+            for algorithm in &["greedy", "optimal"] {
+                let narrow = format!("line-width = 20\nwrap-algorithm = \"{}\"", algorithm);
+                let wide = format!("line-width = 80\nwrap-algorithm = \"{}\"", algorithm);
+                let wrapped = format(source, &narrow);
+
+                assert_eq!(format(&wrapped, &narrow), wrapped);
+                assert_eq!(format(&wrapped, &wide), format(source, &wide));
+            }
+        }
+    }
+
+    #[test]
+    fn overlong_atoms_in_list_items_overflow_after_the_prefix() {
+        let source = "* word `a-very-long-code-span-that-cannot-break`\n\
+                      * `a-very-long-code-span-that-cannot-break` word\n\
+                      * see <https://example.com/a/very/long/path/that/overflows>\n";
+        let formatted = format(source, "line-width = 20");
+
+        assert_eq!(
+            formatted,
+            "* word\n  `a-very-long-code-span-that-cannot-break`\n\
+             * `a-very-long-code-span-that-cannot-break`\n  word\n\
+             * see\n  <https://example.com/a/very/long/path/that/overflows>\n"
+        );
+        assert!(formatted.lines().all(|line| line.trim() != "*"));
+        assert_eq!(format(&formatted, "line-width = 20"), formatted);
+    }
+
+    #[test]
+    fn full_width_rules_fit_inside_their_prefix() {
+        let options = "line-width = 30\nthematic-break-width = 0";
+        let formatted = format("***\n\n> * a\n>\n>   ***\n", options);
+
+        assert_eq!(
+            formatted,
+            format!("{}\n\n> * a\n>\n>   {}\n", "-".repeat(30), "-".repeat(26))
+        );
+    }
+
+    #[test]
+    fn rules_in_list_items_switch_away_from_the_bullet() {
+        let options = "list-delim = \"-\"\nthematic-break = \"-\"";
+        let source = "- ***\n- b\n\n> * ---\n";
+        let formatted = format(source, options);
+
+        assert_eq!(formatted, "- ***\n- b\n\n> - ***\n");
+        assert_eq!(format(&formatted, options), formatted);
+        assert_round_trip(source, options);
+    }
+
+    #[test]
+    fn code_blocks_with_backticks_in_the_info_string_use_tilde_fences() {
+        let source = "~~~ a`b\ncode ~~~~ here\n~~~~~\n";
+        let formatted = format(source, "");
+
+        assert_eq!(formatted, "~~~~~a`b\ncode ~~~~ here\n~~~~~\n");
+        assert_eq!(format(&formatted, ""), formatted);
+    }
+
+    #[test]
+    fn adjacent_lists_keep_different_symbols() {
+        let source = "- a\n- b\n\n* c\n* d\n\n+ e\n\n1. x\n\n1) y\n\n1. z\n";
+        let formatted = format(source, "");
+
+        assert_eq!(
+            formatted,
+            "* a\n* b\n\n- c\n- d\n\n* e\n\n1. x\n\n1) y\n\n1. z\n"
+        );
+        assert_eq!(format(&formatted, ""), formatted);
+    }
+
+    #[test]
+    fn underscore_emphasis_falls_back_to_asterisks_inside_of_words() {
+        let source = "Some *emph* and foo*bar*baz and *a*.\n";
+        let formatted = format(source, "emphasis = \"_\"");
+
+        assert_eq!(formatted, "Some _emph_ and foo*bar*baz and _a_.\n");
+        assert_eq!(format(&formatted, "emphasis = \"_\""), formatted);
+    }
+
+    /// Words that exercise escaping, atoms, wide characters and overflowing lines
+    const WORDS: &[&str] = &[
+        "a",
+        "word",
+        "wrapping",
+        "paragraph",
+        "1.",
+        "2)",
+        "#",
+        "-",
+        "+",
+        ">",
+        "*emphasis*",
+        "**strong text**",
+        "`code span`",
+        "[link](https://example.com)",
+        "https://example.com/path",
+        "中文字符",
+        "🦀",
+        "élève",
+        "antidisestablishmentarianism-and-then-some-more",
+    ];
+
+    /// A paragraph made up of words from `WORDS`
+    ///
+    /// Words are separated by single spaces, runs of spaces and soft breaks. Soft breaks are
+    /// followed by a plain word, so the source lines themselves never start a new block.
+    fn paragraph() -> impl Strategy<Value = String> {
+        let word = prop::sample::select(WORDS);
+        let separator = prop::sample::select(&[" ", "  ", "\nsoft "][..]);
+
+        prop::collection::vec((separator, word), 1..40).prop_map(|words| {
+            let mut paragraph = "start".to_owned();
+
+            for (separator, word) in words {
+                paragraph.push_str(separator);
+                paragraph.push_str(word);
+            }
+            paragraph
+        })
+    }
+
+    /// A paragraph nested inside of any combination of block quotes and list items
+    fn document() -> impl Strategy<Value = String> {
+        let container = prop::sample::select(&["> ", "* ", "- ", "1. "][..]);
+
+        (prop::collection::vec(container, 0..4), paragraph())
+            .prop_map(|(containers, paragraph)| format!("{}{}\n", containers.concat(), paragraph))
+    }
+
+    /// The options for any line width and either wrap algorithm
+    fn options() -> impl Strategy<Value = String> {
+        let algorithm = prop::sample::select(&["greedy", "optimal"][..]);
+        let indent_width = prop::sample::select(&[2, 4][..]);
+
+        (20..100usize, algorithm, indent_width).prop_map(|(width, algorithm, indent_width)| {
+            format!(
+                "line-width = {}\nwrap-algorithm = \"{}\"\nindent-width = {}",
+                width, algorithm, indent_width
+            )
+        })
+    }
+
+    /// Strip the container prefix from the start of a line
+    fn strip_prefix(line: &str) -> &str {
+        line.trim_start_matches(|c: char| " >*-+.)".contains(c) || c.is_ascii_digit())
+    }
+
+    proptest! {
+        #[test]
+        fn wrapped_lines_never_end_with_whitespace(source in document(), options in options()) {
+            let formatted = format(&source, &options);
+
+            for line in formatted.lines() {
+                prop_assert_eq!(line, line.trim_end());
+            }
+        }
+
+        #[test]
+        fn wrapped_lines_always_have_content(source in document(), options in options()) {
+            let formatted = format(&source, &options);
+
+            for line in formatted.lines() {
+                prop_assert!(!strip_prefix(line).is_empty(), "prefix-only line: {:?}", line);
+            }
+        }
+
+        #[test]
+        fn wrapped_lines_never_double_spaces(source in document(), options in options()) {
+            let formatted = format(&source, &options);
+
+            for line in formatted.lines() {
+                prop_assert!(!strip_prefix(line).contains("  "), "doubled space: {:?}", line);
+            }
+        }
     }
-    // This branch only triggers when the `document` node is passed in, which is a sentinel node
-    // that doesn't have any information
-    None
 }
@@ -1,5 +1,6 @@
 mod config;
 
+mod editorconfig;
 mod formatter;
 mod parser;
 
@@ -8,24 +9,117 @@ use config::{Config, Opt};
 use failure::Error;
 use formatter::Formatter;
 use parser::parse;
-use std::{fs, rc::Rc};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+};
 use structopt::StructOpt;
+use walkdir::WalkDir;
 
-fn main() -> Result<(), Error> {
+fn main() {
+    // Errors are displayed rather than debug printed, because some of them (such as config
+    // violations) are written for people to read
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
     let opt = Opt::from_args();
 
     // Check whether the CLI arguments are valid inputs
     opt.valid()?;
-    let contents = fs::read_to_string(opt.clone().input_file)?;
+
+    if opt.config_schema {
+        println!("{}", Config::json_schema()?);
+        return Ok(());
+    }
+
+    match &opt.print_config {
+        Some(Some(_)) => {
+            print!("{}", Config::default().to_annotated_toml()?);
+            return Ok(());
+        }
+        Some(None) => {
+            print!("{}", Config::try_from(opt.clone())?.to_annotated_toml()?);
+            return Ok(());
+        }
+        None => (),
+    }
+
+    // The input file is required unless the config is printed
+    let input_file = opt.input_file.clone().expect("no input file");
+
+    let files = if input_file.is_dir() {
+        markdown_files(&input_file)?
+    } else {
+        vec![input_file]
+    };
+
+    // Every config is resolved before anything is written, so an invalid config for one file
+    // doesn't leave the files before it formatted and the ones after it untouched
+    let mut configs = Vec::with_capacity(files.len());
+    for file in &files {
+        configs.push(Rc::new(Config::load(&opt, Some(file))?));
+    }
+
+    for (file, config) in files.iter().zip(configs) {
+        format_file(&opt, file, config)?;
+    }
+    Ok(())
+}
+
+/// Find every markdown file inside of a directory, sorted by path
+///
+/// Hidden directories (such as `.git`) and the build output of Rust and JavaScript projects
+/// (`target` and `node_modules`) are skipped.
+fn markdown_files(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let walker = WalkDir::new(directory)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            let skipped = name.starts_with('.') || name == "target" || name == "node_modules";
+            entry.depth() == 0 || !entry.file_type().is_dir() || !skipped
+        });
+
+    for entry in walker {
+        let entry = entry?;
+        let is_markdown = entry
+            .path()
+            .extension()
+            .is_some_and(|extension| extension == "md" || extension == "markdown");
+
+        if entry.file_type().is_file() && is_markdown {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+/// Format a single markdown file with the config that applies to it
+fn format_file(opt: &Opt, input_file: &Path, config: Rc<Config>) -> Result<(), Error> {
+    let contents = fs::read_to_string(input_file)?;
     let arena = Arena::new();
     let root = parse(&arena, &contents);
-    let config = Rc::new(Config::from(opt.clone()));
-    let mut fmt = Formatter::new(config);
-    let formatted_doc = fmt.format_md(&root);
-
-    // TODO(afnan) remove
-    debugln!("[START DOCUMENT]");
-    print!("{}", formatted_doc);
-    debugln!("[END DOCUMENT]");
+    let mut fmt = Formatter::new(config, root, &contents);
+    let formatted_doc = fmt.format_md(root);
+
+    if opt.in_place {
+        // Files that are already formatted are left alone, so their modification time is kept
+        if formatted_doc != contents {
+            fs::write(input_file, formatted_doc)?;
+        }
+    } else {
+        // TODO(afnan) remove
+        debugln!("[START DOCUMENT]");
+        print!("{}", formatted_doc);
+        debugln!("[END DOCUMENT]");
+    }
     Ok(())
 }
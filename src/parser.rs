@@ -2,14 +2,23 @@
 //!
 //! This module exists to separate the implementation of comrak from our own code.
 
-use comrak::{nodes::AstNode, parse_document, Arena, ComrakOptions};
+use comrak::{
+    nodes::{AstNode, NodeLink, NodeValue},
+    parse_document, Arena, ComrakOptions,
+};
+use std::collections::{HashMap, VecDeque};
 
 /// Parse the contents of a document to a tree
 ///
 /// Return the root node of the tree. You must construct the arena that nodes will be allocated to
 /// and pass them to this method.
 pub fn parse<'a>(arena: &'a Arena<AstNode<'a>>, contents: &str) -> &'a AstNode<'a> {
-    let options = ComrakOptions {
+    parse_document(arena, contents, &options())
+}
+
+/// The options that documents are parsed with
+pub fn options() -> ComrakOptions {
+    ComrakOptions {
         smart: true,
         width: 79,
         ext_strikethrough: true,
@@ -18,6 +27,391 @@ pub fn parse<'a>(arena: &'a Arena<AstNode<'a>>, contents: &str) -> &'a AstNode<'
         ext_tasklist: true,
         ext_superscript: true,
         ..ComrakOptions::default()
+    }
+}
+
+/// Find out which autolinks are wrapped in angle brackets, in the order they were written
+///
+/// The parser represents autolinks such as `<https://example.com>` the same way as bare URLs that
+/// are picked up by the autolink extension, so we have to look at the source to tell them apart.
+///
+/// Every autolink's URL maps to the form of each of its occurrences in document order (`true`
+/// for angle brackets), so the same URL can be written both ways in one document. Email autolinks
+/// are keyed by the `mailto:` scheme the parser gives them.
+///
+/// Inline nodes don't know where they are in the source, so we walk the tree in document order
+/// with a cursor that only moves forward through the source. The cursor jumps to the first line of
+/// every block, past raw inline HTML and over the whole source of regular links, so URLs in HTML
+/// attributes, link text and link titles are never mistaken for autolinks. Code and HTML blocks
+/// and code spans are blanked out before we start.
+pub fn angle_autolinks<'a>(
+    root: &'a AstNode<'a>,
+    contents: &str,
+) -> HashMap<String, VecDeque<bool>> {
+    let text = mask_code(root, contents);
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+        .collect();
+
+    let mut autolinks: HashMap<String, VecDeque<bool>> = HashMap::new();
+    let mut cursor = 0;
+
+    for node in root.descendants() {
+        let ast = node.data.borrow();
+
+        // Only blocks have a line number
+        if ast.start_line > 0 {
+            if let Some(&line_start) = line_starts.get(ast.start_line as usize - 1) {
+                cursor = cursor.max(line_start);
+            }
+        }
+
+        match ast.value {
+            NodeValue::HtmlInline(ref literal) => {
+                let literal = String::from_utf8_lossy(literal);
+                if let Some(start) = text[cursor..].find(literal.as_ref()) {
+                    cursor += start + literal.len();
+                }
+            }
+            NodeValue::Link(ref link) | NodeValue::Image(ref link) => {
+                let literal = match autolink_literal(node, link) {
+                    Some(literal) if matches!(ast.value, NodeValue::Link(_)) => literal,
+                    // The source of a regular link starts at its opening bracket
+                    _ => {
+                        if let Some(start) = find_unescaped(&text, cursor, '[') {
+                            cursor = skip_link(&text, start);
+                        }
+                        continue;
+                    }
+                };
+
+                let start = match text[cursor..].find(literal.as_str()) {
+                    Some(start) => cursor + start,
+                    None => continue,
+                };
+                let before = &text[..start];
+                let after = &text[start + literal.len()..];
+
+                // The URL may also be the text of a regular link
+                if before.ends_with('[') && !is_escaped(&text, start - 1) {
+                    cursor = skip_link(&text, start - 1);
+                    continue;
+                }
+
+                let angled = before.ends_with('<')
+                    && !is_escaped(&text, start - 1)
+                    && after.starts_with('>');
+                cursor = start + literal.len() + angled as usize;
+
+                // Bare `www.` links can't be written in angle brackets
+                let url = String::from_utf8_lossy(&link.url).into_owned();
+                if url != format!("http://{}", literal) {
+                    autolinks.entry(url).or_default().push_back(angled);
+                }
+            }
+            _ => (),
+        }
+    }
+    autolinks
+}
+
+/// Get the literal text of a link that can be written as an autolink
+///
+/// These are the links whose only child is text that spells out the URL, either exactly, without
+/// the `mailto:` scheme of an email, or without the `http://` scheme of a bare `www.` link.
+fn autolink_literal<'a>(node: &'a AstNode<'a>, link: &NodeLink) -> Option<String> {
+    let mut children = node.children();
+    let literal = match (children.next(), children.next()) {
+        (Some(child), None) => match child.data.borrow().value {
+            NodeValue::Text(ref literal) => String::from_utf8_lossy(literal).into_owned(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let url = String::from_utf8_lossy(&link.url);
+    let spelled_out = url == literal
+        || url == format!("mailto:{}", literal)
+        || url == format!("http://{}", literal);
+
+    if link.title.is_empty() && spelled_out {
+        Some(literal)
+    } else {
+        None
+    }
+}
+
+/// Determine whether the character at a byte offset is escaped with a backslash
+fn is_escaped(text: &str, index: usize) -> bool {
+    text[..index]
+        .chars()
+        .rev()
+        .take_while(|c| *c == '\\')
+        .count()
+        % 2
+        == 1
+}
+
+/// Find the first unescaped occurrence of a character at or after a byte offset
+fn find_unescaped(text: &str, from: usize, target: char) -> Option<usize> {
+    text[from..]
+        .match_indices(target)
+        .map(|(index, _)| from + index)
+        .find(|index| !is_escaped(text, *index))
+}
+
+/// Find the end of the link whose text starts with the bracket at a byte offset
+///
+/// This skips the bracketed link text, followed by either an inline destination and title in
+/// parentheses or a reference label in brackets, if there is one. The byte offset right after the
+/// link is returned.
+fn skip_link(text: &str, start: usize) -> usize {
+    let chars: Vec<(usize, char)> = text[start..]
+        .char_indices()
+        .map(|(index, c)| (start + index, c))
+        .collect();
+    let mut position = 0;
+
+    // Walk up to the bracket that closes the link text
+    let mut depth = 0;
+    while position < chars.len() {
+        match chars[position].1 {
+            '\\' => position += 1,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ => (),
+        }
+        position += 1;
+    }
+    position += 1;
+
+    let closing = match chars.get(position).map(|(_, c)| *c) {
+        Some('(') => ')',
+        Some('[') => ']',
+        _ => return chars.get(position).map_or(text.len(), |(index, _)| *index),
     };
-    parse_document(arena, contents, &options)
+
+    // Destinations can be wrapped in angle brackets or contain balanced parentheses, and titles
+    // are quoted, so any of them may contain the closing character
+    let mut quote = None;
+    let mut parens = 0;
+    position += 1;
+    while position < chars.len() {
+        let c = chars[position].1;
+        match quote {
+            _ if c == '\\' => position += 1,
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == closing && parens == 0 => return chars[position].0 + 1,
+            None if closing == ')' && c == ')' => parens -= 1,
+            None if closing == ')' && c == '(' => parens += 1,
+            None if closing == ')' && (c == '"' || c == '\'') => quote = Some(c),
+            None if closing == ')' && c == '<' => quote = Some('>'),
+            None => (),
+        }
+        position += 1;
+    }
+    text.len()
+}
+
+/// Blank out the parts of the source that are code or raw HTML
+///
+/// Every masked character is replaced with spaces of the same length, so byte offsets into the
+/// masked text are the same as in the source. Code and HTML blocks are found through the tree,
+/// and code spans are found by matching up runs of backticks in the rest of the text.
+fn mask_code<'a>(root: &'a AstNode<'a>, contents: &str) -> String {
+    let mut masked_lines = Vec::new();
+
+    for node in root.descendants() {
+        let ast = node.data.borrow();
+        let start = ast.start_line as usize;
+
+        match ast.value {
+            NodeValue::CodeBlock(ref block) => {
+                let lines = block.literal.split(|b| *b == b'\n').count() - 1;
+
+                // Fenced code blocks also have an opening and (usually) a closing fence
+                if block.fenced {
+                    masked_lines.push(start..=start + lines + 1);
+                } else {
+                    masked_lines.push(start..=start + lines.saturating_sub(1));
+                }
+            }
+            NodeValue::HtmlBlock(ref block) => {
+                let lines = block.literal.split(|b| *b == b'\n').count() - 1;
+                masked_lines.push(start..=start + lines.saturating_sub(1));
+            }
+            _ => (),
+        }
+    }
+
+    // Whole lines of blocks are blanked out first
+    let mut chars: Vec<char> = Vec::with_capacity(contents.len());
+
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        if masked_lines
+            .iter()
+            .any(|lines| lines.contains(&(index + 1)))
+        {
+            chars.extend(line.chars().map(|c| if c == '\n' { '\n' } else { ' ' }));
+        } else {
+            chars.extend(line.chars());
+        }
+    }
+
+    // A code span starts with a run of backticks, and ends at the next run of the same length
+    // that comes before the end of the paragraph
+    let mut position = 0;
+
+    while position < chars.len() {
+        let run = backtick_run(&chars, position);
+        let escaped = chars[..position]
+            .iter()
+            .rev()
+            .take_while(|c| **c == '\\')
+            .count()
+            % 2
+            == 1;
+
+        if run == 0 || escaped {
+            position += 1;
+            continue;
+        }
+
+        let mut end = position + run;
+        let mut closing = None;
+
+        while end < chars.len() {
+            let next_run = backtick_run(&chars, end);
+
+            if next_run == run {
+                closing = Some(end + run);
+                break;
+            } else if next_run > 0 {
+                end += next_run;
+            } else if chars[end] == '\n' && is_blank_line(&chars[end + 1..]) {
+                break;
+            } else {
+                end += 1;
+            }
+        }
+
+        match closing {
+            Some(closing) => {
+                for c in &mut chars[position..closing] {
+                    if *c != '\n' {
+                        *c = ' ';
+                    }
+                }
+                position = closing;
+            }
+            None => position += run,
+        }
+    }
+
+    // Masked characters keep the length of the original ones
+    contents
+        .chars()
+        .zip(chars)
+        .map(|(original, c)| {
+            if c == original {
+                original.to_string()
+            } else {
+                " ".repeat(original.len_utf8())
+            }
+        })
+        .collect()
+}
+
+/// Get the length of the run of backticks that starts at a position
+fn backtick_run(chars: &[char], position: usize) -> usize {
+    chars[position..].iter().take_while(|c| **c == '`').count()
+}
+
+/// Determine whether the text that follows a line break starts with a blank line
+fn is_blank_line(rest: &[char]) -> bool {
+    rest.iter()
+        .take_while(|c| **c != '\n')
+        .all(|c| c.is_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the form of every autolink in a document, keyed by URL
+    fn forms(source: &str) -> Vec<(String, Vec<bool>)> {
+        let arena = Arena::new();
+        let root = parse(&arena, source);
+        let mut forms: Vec<(String, Vec<bool>)> = angle_autolinks(root, source)
+            .into_iter()
+            .map(|(url, forms)| (url, forms.into_iter().collect()))
+            .collect();
+        forms.sort();
+        forms
+    }
+
+    /// The forms of the autolinks to a single URL
+    fn single(url: &str, forms: &[bool]) -> Vec<(String, Vec<bool>)> {
+        vec![(url.to_owned(), forms.to_vec())]
+    }
+
+    #[test]
+    fn each_occurrence_keeps_its_form() {
+        let source = "See https://a.com and <https://a.com>, then https://a.com.\n";
+        assert_eq!(
+            forms(source),
+            single("https://a.com", &[false, true, false])
+        );
+    }
+
+    #[test]
+    fn code_is_skipped() {
+        let source =
+            "`<https://a.com>` and https://a.com\n\n```\n<https://a.com>\n```\n\n<https://a.com>\n";
+        assert_eq!(forms(source), single("https://a.com", &[false, true]));
+    }
+
+    #[test]
+    fn html_attributes_are_skipped() {
+        let source = "<a href=\"https://a.com\">a</a> and <https://a.com>\n";
+        assert_eq!(forms(source), single("https://a.com", &[true]));
+    }
+
+    #[test]
+    fn link_text_and_titles_are_skipped() {
+        let source = "[https://a.com docs](https://b.com) and <https://a.com>\n";
+        assert_eq!(forms(source), single("https://a.com", &[true]));
+
+        let source = "[docs](https://b.com \"https://a.com\") and <https://a.com>\n";
+        assert_eq!(forms(source), single("https://a.com", &[true]));
+
+        let source = "[docs](<https://a.com> \"(t)\") and https://a.com\n";
+        assert_eq!(forms(source), single("https://a.com", &[false]));
+    }
+
+    #[test]
+    fn escaped_angle_brackets_are_bare() {
+        // The autolink extension takes the closing bracket to be a part of the URL
+        assert_eq!(
+            forms("\\<https://a.com> and <https://a.com>\n"),
+            vec![
+                ("https://a.com".to_owned(), vec![true]),
+                ("https://a.com>".to_owned(), vec![false]),
+            ]
+        );
+    }
+
+    #[test]
+    fn emails_are_keyed_by_their_scheme() {
+        assert_eq!(
+            forms("<me@x.org> and me@x.org\n"),
+            single("mailto:me@x.org", &[true, false])
+        );
+    }
 }